@@ -0,0 +1,108 @@
+use std::error::Error;
+
+use regex::Regex;
+use similar::{ChangeTag, TextDiff};
+
+// How many offending lines are quoted per failed assertion
+const MAX_QUOTED_LINES: usize = 5;
+
+/// Machine-checkable expectations attached to the instruction, checked on
+/// every candidate before it is written or validated.
+pub struct Assertions {
+    /// Patterns that must not match the new content.
+    absent: Vec<Regex>,
+    /// Patterns that must match the new content of every changed file.
+    present: Vec<Regex>,
+    /// Most added plus removed lines allowed in one file.
+    max_changed_lines: Option<usize>,
+}
+
+impl Assertions {
+    pub fn new(absent: &[String], present: &[String], max_changed_lines: Option<usize>) -> Result<Self, Box<dyn Error>> {
+        let compile = |patterns: &[String]| -> Result<Vec<Regex>, Box<dyn Error>> {
+            patterns
+                .iter()
+                .map(|pattern| Regex::new(pattern).map_err(|e| format!("Invalid assertion pattern `{}`: {}", pattern, e).into()))
+                .collect()
+        };
+        Ok(Assertions {
+            absent: compile(absent)?,
+            present: compile(present)?,
+            max_changed_lines,
+        })
+    }
+
+    /// Explains every assertion that `candidate`, the new content of a file
+    /// that contained `original`, fails. Empty if it passes them all.
+    pub fn check(&self, original: &str, candidate: &str) -> Vec<String> {
+        let mut failures = Vec::new();
+
+        for pattern in &self.absent {
+            let offending: Vec<String> = candidate
+                .lines()
+                .enumerate()
+                .filter(|(_, line)| pattern.is_match(line))
+                .map(|(number, line)| format!("line {}: {}", number + 1, line.trim()))
+                .collect();
+            // Multi-line patterns cannot be pinned to one line
+            if !offending.is_empty() || pattern.is_match(candidate) {
+                failures.push(format!(
+                    "`{}` must not match the new content, but it does{}",
+                    pattern,
+                    quote_lines(&offending)
+                ));
+            }
+        }
+
+        if candidate != original {
+            for pattern in &self.present {
+                if !pattern.is_match(candidate) {
+                    failures.push(format!("`{}` must match the new content, but it does not", pattern));
+                }
+            }
+        }
+
+        if let Some(limit) = self.max_changed_lines {
+            let changed = TextDiff::from_lines(original, candidate)
+                .iter_all_changes()
+                .filter(|change| change.tag() != ChangeTag::Equal)
+                .count();
+            if changed > limit {
+                failures.push(format!(
+                    "at most {} lines may be added or removed, but the change touches {}; change only what the instruction asks for",
+                    limit, changed
+                ));
+            }
+        }
+
+        failures
+    }
+}
+
+fn quote_lines(lines: &[String]) -> String {
+    if lines.is_empty() {
+        return String::new();
+    }
+    let mut quoted = String::from(" on:");
+    for line in lines.iter().take(MAX_QUOTED_LINES) {
+        quoted.push_str(&format!("\n  {}", line));
+    }
+    if lines.len() > MAX_QUOTED_LINES {
+        quoted.push_str(&format!("\n  ... {} more lines", lines.len() - MAX_QUOTED_LINES));
+    }
+    quoted
+}
+
+/// Explains a rejected attempt to the model: what it changed and which
+/// assertions the result failed.
+pub fn feedback_prompt(diff: &str, failures: &[String]) -> String {
+    let mut prompt = format!(
+        "<PREVIOUS_ATTEMPT>\nYour previous attempt made the changes below, but the result does not meet the requirements attached to the instruction. Fix it while still following the instruction.\n\n<PREVIOUS_DIFF>\n{}</PREVIOUS_DIFF>\n\n<FAILED_ASSERTIONS>\n",
+        diff
+    );
+    for failure in failures {
+        prompt.push_str(&format!("- {}\n", failure));
+    }
+    prompt.push_str("</FAILED_ASSERTIONS>\n</PREVIOUS_ATTEMPT>");
+    prompt
+}
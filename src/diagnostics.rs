@@ -0,0 +1,474 @@
+use std::env;
+use std::error::Error;
+use std::fmt;
+use std::path::{Component, Path, PathBuf};
+use std::sync::LazyLock;
+
+use regex::Regex;
+
+/// Names accepted by `--diagnostics-format`.
+pub const DIAGNOSTICS_FORMATS: &[&str] = &["auto", "cargo", "tsc", "pytest", "eslint", "generic"];
+
+/// Which parser reads the validation command's output.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DiagnosticsFormat {
+    /// Detect the validator from its output.
+    Auto,
+    /// `cargo ... --message-format=json`.
+    Cargo,
+    /// `tsc`, plain or `--pretty`.
+    Tsc,
+    /// pytest's summary lines or JUnit XML.
+    Pytest,
+    /// `eslint -f json`.
+    Eslint,
+    /// `file:line:col: message` lines, as printed by gcc, clang, ruff, mypy, go vet and friends.
+    Generic,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Severity {
+    Error,
+    Warning,
+    Note,
+}
+
+/// One problem reported by a validator, attributed to a file where possible.
+#[derive(Debug, Clone)]
+pub struct Diagnostic {
+    pub severity: Severity,
+    pub file: Option<PathBuf>,
+    pub line: Option<u32>,
+    pub column: Option<u32>,
+    pub code: Option<String>,
+    pub message: String,
+}
+
+impl Diagnostic {
+    /// Whether the diagnostic points at `path`, ignoring `./` prefixes and
+    /// whether either side is absolute.
+    pub fn is_in(&self, path: &Path) -> bool {
+        let normalize = |path: &Path| -> PathBuf {
+            let path = if path.is_absolute() {
+                path.to_path_buf()
+            } else {
+                env::current_dir().unwrap_or_default().join(path)
+            };
+            path.components().filter(|c| !matches!(c, Component::CurDir)).collect()
+        };
+        self.file.as_deref().is_some_and(|file| normalize(file) == normalize(path))
+    }
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let Some(file) = &self.file {
+            write!(f, "{}", file.display())?;
+            if let Some(line) = self.line {
+                write!(f, ":{}", line)?;
+                if let Some(column) = self.column {
+                    write!(f, ":{}", column)?;
+                }
+            }
+            write!(f, ": ")?;
+        }
+        let severity = match self.severity {
+            Severity::Error => "error",
+            Severity::Warning => "warning",
+            Severity::Note => "note",
+        };
+        match &self.code {
+            Some(code) => write!(f, "{}[{}]: {}", severity, code, self.message),
+            None => write!(f, "{}: {}", severity, self.message),
+        }
+    }
+}
+
+impl DiagnosticsFormat {
+    pub fn from_name(name: &str) -> Result<Self, Box<dyn Error>> {
+        match name {
+            "auto" => Ok(DiagnosticsFormat::Auto),
+            "cargo" => Ok(DiagnosticsFormat::Cargo),
+            "tsc" => Ok(DiagnosticsFormat::Tsc),
+            "pytest" => Ok(DiagnosticsFormat::Pytest),
+            "eslint" => Ok(DiagnosticsFormat::Eslint),
+            "generic" => Ok(DiagnosticsFormat::Generic),
+            other => Err(format!("Unknown diagnostics format: {}", other).into()),
+        }
+    }
+
+    /// Parses the validator's combined output. `Auto` uses the first
+    /// structured parser that recognises anything, then the generic one.
+    pub fn parse(&self, output: &str) -> Vec<Diagnostic> {
+        match self {
+            DiagnosticsFormat::Auto => [parse_cargo, parse_eslint, parse_tsc, parse_pytest]
+                .iter()
+                .map(|parser| parser(output))
+                .find(|diagnostics| !diagnostics.is_empty())
+                .unwrap_or_else(|| parse_generic(output)),
+            DiagnosticsFormat::Cargo => parse_cargo(output),
+            DiagnosticsFormat::Tsc => parse_tsc(output),
+            DiagnosticsFormat::Pytest => parse_pytest(output),
+            DiagnosticsFormat::Eslint => parse_eslint(output),
+            DiagnosticsFormat::Generic => parse_generic(output),
+        }
+    }
+}
+
+fn parse_cargo(output: &str) -> Vec<Diagnostic> {
+    output
+        .lines()
+        .filter(|line| line.starts_with('{'))
+        .filter_map(|line| serde_json::from_str::<serde_json::Value>(line).ok())
+        .filter(|entry| entry["reason"] == "compiler-message")
+        .filter_map(|entry| {
+            let message = &entry["message"];
+            let severity = match message["level"].as_str()? {
+                "error" | "error: internal compiler error" => Severity::Error,
+                "warning" => Severity::Warning,
+                _ => Severity::Note,
+            };
+            let text = message["message"].as_str()?;
+            // The "N warnings emitted" summary carries no location and repeats the others
+            if message["spans"].as_array().is_some_and(|spans| spans.is_empty()) && text.contains("emitted") {
+                return None;
+            }
+            let span = message["spans"]
+                .as_array()
+                .and_then(|spans| spans.iter().find(|span| span["is_primary"] == true));
+            Some(Diagnostic {
+                severity,
+                file: span.and_then(|span| span["file_name"].as_str()).map(PathBuf::from),
+                line: span.and_then(|span| span["line_start"].as_u64()).map(|line| line as u32),
+                column: span.and_then(|span| span["column_start"].as_u64()).map(|column| column as u32),
+                code: message["code"]["code"].as_str().map(str::to_string),
+                message: text.to_string(),
+            })
+        })
+        .collect()
+}
+
+static TSC_LINE: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(r"^(?P<file>[^\s(:][^(:]*?)(?:\((?P<line>\d+),(?P<col>\d+)\)|:(?P<pline>\d+):(?P<pcol>\d+))\s*(?::|-)\s*(?P<sev>error|warning)\s+(?P<code>TS\d+):\s*(?P<msg>.*)$").unwrap()
+});
+
+fn parse_tsc(output: &str) -> Vec<Diagnostic> {
+    strip_ansi(output)
+        .lines()
+        .filter_map(|line| TSC_LINE.captures(line.trim_end()))
+        .map(|caps| Diagnostic {
+            severity: if &caps["sev"] == "warning" { Severity::Warning } else { Severity::Error },
+            file: Some(PathBuf::from(&caps["file"])),
+            line: caps.name("line").or(caps.name("pline")).and_then(|m| m.as_str().parse().ok()),
+            column: caps.name("col").or(caps.name("pcol")).and_then(|m| m.as_str().parse().ok()),
+            code: Some(caps["code"].to_string()),
+            message: caps["msg"].to_string(),
+        })
+        .collect()
+}
+
+static PYTEST_SUMMARY: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"^(?P<kind>FAILED|ERROR) (?P<file>[^\s:]+)(?:::(?P<test>\S+))?(?: - (?P<msg>.*))?$").unwrap());
+static PYTEST_LOCATION: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"^(?P<file>[^\s:]+\.py):(?P<line>\d+): (?P<msg>\w+(?:Error|Exception)\b.*)$").unwrap());
+static JUNIT_TESTCASE: LazyLock<Regex> = LazyLock::new(|| Regex::new(r#"<testcase\b(?P<attrs>[^>]*)>"#).unwrap());
+static JUNIT_FAILURE: LazyLock<Regex> = LazyLock::new(|| Regex::new(r#"<(?P<kind>failure|error)\b(?P<attrs>[^>]*)>"#).unwrap());
+static XML_ATTRIBUTE: LazyLock<Regex> = LazyLock::new(|| Regex::new(r#"(?P<key>\w+)="(?P<value>[^"]*)""#).unwrap());
+
+fn parse_pytest(output: &str) -> Vec<Diagnostic> {
+    let mut diagnostics = Vec::new();
+
+    // JUnit XML, e.g. `pytest --junitxml=/dev/stdout`
+    for JunitTestCase { attrs, body } in junit_testcases(output) {
+        let Some(failure) = JUNIT_FAILURE.captures(body) else {
+            continue;
+        };
+
+        let name = xml_attribute(attrs, "name").unwrap_or_default();
+        let class = xml_attribute(attrs, "classname").unwrap_or_default();
+        let message = xml_attribute(&failure["attrs"], "message").unwrap_or_default();
+        diagnostics.push(Diagnostic {
+            severity: Severity::Error,
+            file: xml_attribute(attrs, "file").map(PathBuf::from),
+            line: xml_attribute(attrs, "line").and_then(|line| line.parse().ok()),
+            column: None,
+            code: Some(failure["kind"].to_string()),
+            message: format!("{} {}: {}", class, name, message).trim().to_string(),
+        });
+    }
+    if !diagnostics.is_empty() {
+        return diagnostics;
+    }
+
+    // Plain pytest output: the short test summary, plus assertion locations
+    for line in output.lines().map(str::trim_end) {
+        if let Some(caps) = PYTEST_SUMMARY.captures(line) {
+            let test = caps.name("test").map(|m| m.as_str()).unwrap_or("");
+            let message = caps.name("msg").map(|m| m.as_str()).unwrap_or("");
+            diagnostics.push(Diagnostic {
+                severity: Severity::Error,
+                file: Some(PathBuf::from(&caps["file"])),
+                line: None,
+                column: None,
+                code: Some(caps["kind"].to_lowercase()),
+                message: format!("{} {}", test, message).trim().to_string(),
+            });
+        } else if let Some(caps) = PYTEST_LOCATION.captures(line) {
+            diagnostics.push(Diagnostic {
+                severity: Severity::Error,
+                file: Some(PathBuf::from(&caps["file"])),
+                line: caps["line"].parse().ok(),
+                column: None,
+                code: None,
+                message: caps["msg"].to_string(),
+            });
+        }
+    }
+    diagnostics
+}
+
+fn parse_eslint(output: &str) -> Vec<Diagnostic> {
+    let Some(start) = output.find("[{") else {
+        return Vec::new();
+    };
+    let Some(end) = output.rfind("}]") else {
+        return Vec::new();
+    };
+    let Ok(serde_json::Value::Array(files)) = serde_json::from_str::<serde_json::Value>(&output[start..end + 2]) else {
+        return Vec::new();
+    };
+
+    files
+        .iter()
+        .flat_map(|file| {
+            let path = file["filePath"].as_str().map(PathBuf::from);
+            file["messages"]
+                .as_array()
+                .into_iter()
+                .flatten()
+                .map(move |message| Diagnostic {
+                    severity: if message["severity"] == 2 { Severity::Error } else { Severity::Warning },
+                    file: path.clone(),
+                    line: message["line"].as_u64().map(|line| line as u32),
+                    column: message["column"].as_u64().map(|column| column as u32),
+                    code: message["ruleId"].as_str().map(str::to_string),
+                    message: message["message"].as_str().unwrap_or_default().to_string(),
+                })
+        })
+        .collect()
+}
+
+static GENERIC_LINE: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(r"^(?P<arrow>\s*-->\s*)?(?P<file>[^\s:]+):(?P<line>\d+)(?::(?P<col>\d+))?:?\s*(?:(?P<sev>error|warning|note|fatal error)(?:\[(?P<code>[^\]]*)\])?:?\s*)?(?P<msg>.*)$").unwrap()
+});
+static HEADLINE: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"^(?P<sev>error|warning)(?:\[(?P<code>[^\]]*)\])?: (?P<msg>.+)$").unwrap());
+
+fn parse_generic(output: &str) -> Vec<Diagnostic> {
+    let mut diagnostics = Vec::new();
+    // rustc-style output puts the message on the line before the `--> file:line:col` arrow
+    let mut headline: Option<(Severity, Option<String>, String)> = None;
+
+    for line in strip_ansi(output).lines() {
+        let line = line.trim_end();
+        if let Some(caps) = HEADLINE.captures(line) {
+            let severity = if &caps["sev"] == "warning" { Severity::Warning } else { Severity::Error };
+            headline = Some((severity, caps.name("code").map(|m| m.as_str().to_string()), caps["msg"].to_string()));
+            continue;
+        }
+        let Some(caps) = GENERIC_LINE.captures(line) else {
+            continue;
+        };
+        if Path::new(&caps["file"]).extension().is_none() {
+            continue;
+        }
+
+        let (severity, code, message) = match (caps.name("arrow"), headline.take()) {
+            (Some(_), Some(headline)) => headline,
+            _ => (
+                match caps.name("sev").map(|m| m.as_str()) {
+                    Some("warning") => Severity::Warning,
+                    Some("note") => Severity::Note,
+                    _ => Severity::Error,
+                },
+                caps.name("code").map(|m| m.as_str().to_string()),
+                caps["msg"].to_string(),
+            ),
+        };
+        diagnostics.push(Diagnostic {
+            severity,
+            file: Some(PathBuf::from(&caps["file"])),
+            line: caps["line"].parse().ok(),
+            column: caps.name("col").and_then(|m| m.as_str().parse().ok()),
+            code,
+            message,
+        });
+    }
+    diagnostics
+}
+
+static ANSI_ESCAPE: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"\x1b\[[0-9;]*m").unwrap());
+
+fn strip_ansi(output: &str) -> std::borrow::Cow<'_, str> {
+    ANSI_ESCAPE.replace_all(output, "")
+}
+
+/// One `<testcase>` element of JUnit XML.
+pub struct JunitTestCase<'a> {
+    /// The attributes inside the opening tag.
+    pub attrs: &'a str,
+    /// Everything up to `</testcase>`; empty for a self-closing test case.
+    pub body: &'a str,
+}
+
+impl JunitTestCase<'_> {
+    pub fn failed(&self) -> bool {
+        JUNIT_FAILURE.is_match(self.body)
+    }
+
+    pub fn skipped(&self) -> bool {
+        self.body.contains("<skipped")
+    }
+}
+
+/// Every `<testcase>` in `output`, in order.
+pub fn junit_testcases(output: &str) -> impl Iterator<Item = JunitTestCase<'_>> {
+    JUNIT_TESTCASE.captures_iter(output).map(move |case| {
+        let attrs = case.name("attrs").unwrap().as_str();
+        // Self-closing test cases have no body
+        let body = if attrs.ends_with('/') {
+            ""
+        } else {
+            let body_start = case.get(0).unwrap().end();
+            let body_end = output[body_start..].find("</testcase>").map_or(output.len(), |end| body_start + end);
+            &output[body_start..body_end]
+        };
+        JunitTestCase { attrs, body }
+    })
+}
+
+pub fn xml_attribute(attrs: &str, key: &str) -> Option<String> {
+    XML_ATTRIBUTE
+        .captures_iter(attrs)
+        .find(|attr| &attr["key"] == key)
+        .map(|attr| unescape_xml(&attr["value"]))
+}
+
+fn unescape_xml(text: &str) -> String {
+    text.replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&quot;", "\"")
+        .replace("&apos;", "'")
+        .replace("&amp;", "&")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn render(diagnostics: Vec<Diagnostic>) -> Vec<String> {
+        diagnostics.iter().map(Diagnostic::to_string).collect()
+    }
+
+    #[test]
+    fn parses_cargo_json() {
+        let output = r#"{"reason":"compiler-artifact","package_id":"demo 0.1.0"}
+{"reason":"compiler-message","message":{"level":"error","message":"cannot find value `x` in this scope","code":{"code":"E0425"},"spans":[{"file_name":"src/main.rs","line_start":2,"column_start":13,"is_primary":true}]}}
+{"reason":"compiler-message","message":{"level":"warning","message":"unused variable: `y`","code":null,"spans":[{"file_name":"src/lib.rs","line_start":1,"column_start":1,"is_primary":false},{"file_name":"src/lib.rs","line_start":5,"column_start":9,"is_primary":true}]}}
+{"reason":"compiler-message","message":{"level":"warning","message":"1 warning emitted","code":null,"spans":[]}}
+error: could not compile `demo` (bin "demo") due to 1 previous error"#;
+        assert_eq!(
+            render(DiagnosticsFormat::Auto.parse(output)),
+            [
+                "src/main.rs:2:13: error[E0425]: cannot find value `x` in this scope",
+                "src/lib.rs:5:9: warning: unused variable: `y`",
+            ]
+        );
+    }
+
+    #[test]
+    fn parses_tsc_plain_and_pretty() {
+        let output = "src/a.ts(3,7): error TS2322: Type 'string' is not assignable to type 'number'.\n\
+            \x1b[96msrc/b.ts\x1b[0m:\x1b[93m10\x1b[0m:\x1b[93m1\x1b[0m - \x1b[91merror\x1b[0m\x1b[90m TS2304: \x1b[0mCannot find name 'foo'.\n\
+            \n\
+            10 foo();\n\
+            Found 2 errors in 2 files.\n";
+        assert_eq!(
+            render(DiagnosticsFormat::Auto.parse(output)),
+            [
+                "src/a.ts:3:7: error[TS2322]: Type 'string' is not assignable to type 'number'.",
+                "src/b.ts:10:1: error[TS2304]: Cannot find name 'foo'.",
+            ]
+        );
+    }
+
+    #[test]
+    fn parses_pytest_junit_xml() {
+        let output = r#"<?xml version="1.0" encoding="utf-8"?><testsuites><testsuite name="pytest" errors="0" failures="1" tests="3">
+<testcase classname="tests.test_math" name="test_add" file="tests/test_math.py" line="3" time="0.001" />
+<testcase classname="tests.test_math" name="test_div" file="tests/test_math.py" line="7" time="0.002"><failure message="assert (1 &lt; 2) == False">def test_div(): ...</failure></testcase>
+<testcase classname="tests.test_math" name="test_skip" time="0.000"><skipped message="later" /></testcase>
+</testsuite></testsuites>"#;
+        assert_eq!(
+            render(DiagnosticsFormat::Auto.parse(output)),
+            ["tests/test_math.py:7: error[failure]: tests.test_math test_div: assert (1 < 2) == False"]
+        );
+    }
+
+    #[test]
+    fn parses_plain_pytest_output() {
+        let output = "\
+tests/test_math.py:12: AssertionError
+=========================== short test summary info ============================
+FAILED tests/test_math.py::test_div - AssertionError: assert 1 == 2
+ERROR tests/test_io.py - ModuleNotFoundError: No module named 'missing'
+========================= 1 failed, 1 error in 0.12s ==========================";
+        assert_eq!(
+            render(DiagnosticsFormat::Pytest.parse(output)),
+            [
+                "tests/test_math.py:12: error: AssertionError",
+                "tests/test_math.py: error[failed]: test_div AssertionError: assert 1 == 2",
+                "tests/test_io.py: error[error]: ModuleNotFoundError: No module named 'missing'",
+            ]
+        );
+    }
+
+    #[test]
+    fn parses_eslint_json() {
+        let output = r#"> lint
+[{"filePath":"/repo/src/a.js","messages":[{"ruleId":"no-unused-vars","severity":2,"message":"'x' is defined but never used.","line":1,"column":7},{"ruleId":"semi","severity":1,"message":"Missing semicolon.","line":2,"column":10}],"errorCount":1,"warningCount":1},{"filePath":"/repo/src/b.js","messages":[]}]"#;
+        assert_eq!(
+            render(DiagnosticsFormat::Auto.parse(output)),
+            [
+                "/repo/src/a.js:1:7: error[no-unused-vars]: 'x' is defined but never used.",
+                "/repo/src/a.js:2:10: warning[semi]: Missing semicolon.",
+            ]
+        );
+    }
+
+    #[test]
+    fn parses_generic_lines_and_rustc_headlines() {
+        let output = "\
+src/main.c:3:5: error: 'x' undeclared (first use in this function)
+src/main.c:9:1: warning: control reaches end of non-void function
+app/models.py:4: error: Incompatible types in assignment
+error[E0425]: cannot find value `x` in this scope
+ --> src/main.rs:2:13
+  |
+2 |     println!(\"{}\", x);
+  |                    ^ not found in this scope
+warning: unused import: `std::fs`
+  --> src/lib.rs:1:5
+Build took 0:42:10
+";
+        assert_eq!(
+            render(DiagnosticsFormat::Auto.parse(output)),
+            [
+                "src/main.c:3:5: error: 'x' undeclared (first use in this function)",
+                "src/main.c:9:1: warning: control reaches end of non-void function",
+                "app/models.py:4: error: Incompatible types in assignment",
+                "src/main.rs:2:13: error[E0425]: cannot find value `x` in this scope",
+                "src/lib.rs:1:5: warning: unused import: `std::fs`",
+            ]
+        );
+    }
+}
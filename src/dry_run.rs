@@ -0,0 +1,92 @@
+use std::cell::RefCell;
+use std::env;
+use std::io::{self, IsTerminal};
+use std::path::{Path, PathBuf};
+
+use similar::{ChangeTag, TextDiff};
+
+use crate::validation;
+
+const RED: &str = "\x1b[31m";
+const GREEN: &str = "\x1b[32m";
+const CYAN: &str = "\x1b[36m";
+const BOLD: &str = "\x1b[1m";
+const RESET: &str = "\x1b[0m";
+
+/// Shows accepted changes instead of writing them, and sums them up at the
+/// end of the run.
+pub struct DryRun {
+    /// Every changed path with its added and removed line counts and whether
+    /// it would be created or deleted.
+    changes: RefCell<Vec<(PathBuf, usize, usize, &'static str)>>,
+}
+
+impl DryRun {
+    pub fn new() -> Self {
+        DryRun {
+            changes: RefCell::new(Vec::new()),
+        }
+    }
+
+    /// Prints the diff of one file that would change from `before` to
+    /// `after`; `None` means the file does not exist.
+    pub fn record(&self, path: &Path, before: Option<&str>, after: Option<&str>) {
+        let (old, new) = (before.unwrap_or(""), after.unwrap_or(""));
+        let diff = validation::unified_diff(&path.display().to_string(), old, new);
+        print!("{}", colorize(&diff));
+
+        let (mut added, mut removed) = (0, 0);
+        for change in TextDiff::from_lines(old, new).iter_all_changes() {
+            match change.tag() {
+                ChangeTag::Insert => added += 1,
+                ChangeTag::Delete => removed += 1,
+                ChangeTag::Equal => {}
+            }
+        }
+        let kind = match (before, after) {
+            (None, _) => " (new file)",
+            (_, None) => " (deleted)",
+            _ => "",
+        };
+        self.changes.borrow_mut().push((path.to_path_buf(), added, removed, kind));
+    }
+
+    pub fn print_summary(&self) {
+        let changes = self.changes.borrow();
+        let added: usize = changes.iter().map(|(_, added, _, _)| added).sum();
+        let removed: usize = changes.iter().map(|(_, _, removed, _)| removed).sum();
+        println!(
+            "\nDry run: {} files would change, {} lines added and {} removed. Nothing was written.",
+            changes.len(),
+            added,
+            removed
+        );
+        for (path, added, removed, kind) in changes.iter() {
+            println!("  {}{} +{} -{}", path.display(), kind, added, removed);
+        }
+    }
+}
+
+/// Colors the lines of a unified diff when stdout is a terminal and
+/// `NO_COLOR` is not set.
+pub fn colorize(diff: &str) -> String {
+    if !io::stdout().is_terminal() || env::var_os("NO_COLOR").is_some() {
+        return diff.to_string();
+    }
+    diff.split_inclusive('\n')
+        .map(|line| {
+            let color = if line.starts_with("+++") || line.starts_with("---") {
+                BOLD
+            } else if line.starts_with('+') {
+                GREEN
+            } else if line.starts_with('-') {
+                RED
+            } else if line.starts_with("@@") {
+                CYAN
+            } else {
+                return line.to_string();
+            };
+            format!("{}{}{}\n", color, line.trim_end_matches('\n'), RESET)
+        })
+        .collect()
+}
@@ -0,0 +1,258 @@
+use std::error::Error;
+
+use super::extract_tag;
+
+pub const SYSTEM_PROMPT: &str = "You are an expert code transformation assistant. Your task is to carefully refactor code based on the user's instruction and return only your changes as a unified diff enclosed within <DIFF> tags. Every hunk must start with an `@@ -start,count +start,count @@` header and include up to three unchanged context lines copied exactly from the file. If the file needs no change, answer with an empty <DIFF></DIFF>. Additionally, provide your reasoning inside <REASONING> tags. Do not include any other text outside these tags.";
+
+pub const EXAMPLE_ANSWER: &str = "<DIFF>\n@@ -1,3 +1,3 @@\n-let old_value = 10;\n-let old_name = \"example\";\n+let new_value = 10;\n+let new_name = \"example\";\n let other_var = 5;\n</DIFF>";
+
+// How many leading/trailing context lines a hunk may lose and still apply,
+// like `patch --fuzz`.
+const MAX_FUZZ: usize = 2;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Line<'a> {
+    Context(&'a str),
+    Remove(&'a str),
+    Add(&'a str),
+}
+
+struct Hunk<'a> {
+    header: &'a str,
+    old_start: usize,
+    lines: Vec<Line<'a>>,
+}
+
+#[derive(Clone, Copy)]
+enum Compare {
+    Exact,
+    TrimEnd,
+    Trim,
+}
+
+impl Compare {
+    fn eq(self, a: &str, b: &str) -> bool {
+        let (a, b) = (without_ending(a), without_ending(b));
+        match self {
+            Compare::Exact => a == b,
+            Compare::TrimEnd => a.trim_end() == b.trim_end(),
+            Compare::Trim => a.trim() == b.trim(),
+        }
+    }
+}
+
+/// Applies the `<DIFF>` hunks in `output` to `content`; an empty diff leaves
+/// it unchanged. Fails with a list of the hunks that could not be placed,
+/// leaving the decision to the caller.
+pub fn apply(output: &str, content: &str) -> Result<String, Box<dyn Error>> {
+    let hunks = parse(extract_tag(output, "DIFF")?)?;
+    if hunks.is_empty() {
+        return Ok(content.to_string());
+    }
+
+    // Untouched lines keep their exact bytes, and added ones get the file's line ending
+    let ending = if content.contains("\r\n") { "\r\n" } else { "\n" };
+    let mut lines: Vec<String> = content.split_inclusive('\n').map(str::to_string).collect();
+    let mut floor = 0;
+    let mut offset: isize = 0;
+    let mut failed = Vec::new();
+
+    for (index, hunk) in hunks.iter().enumerate() {
+        let expected = (hunk.old_start.saturating_sub(1) as isize + offset).max(0) as usize;
+        match locate(&lines, hunk, floor, expected) {
+            Some((start, hunk_lines)) => {
+                let mut replacement = Vec::new();
+                let mut cursor = start;
+                for line in &hunk_lines {
+                    match line {
+                        // Keep the file's own context line in case it only matched after whitespace normalisation
+                        Line::Context(_) => {
+                            replacement.push(lines[cursor].clone());
+                            cursor += 1;
+                        }
+                        Line::Remove(_) => cursor += 1,
+                        Line::Add(text) => replacement.push(format!("{}{}", without_ending(text), ending)),
+                    }
+                }
+                let removed = cursor - start;
+                let added = replacement.len();
+                lines.splice(start..cursor, replacement);
+                floor = start + added;
+                offset = start as isize - hunk.old_start.saturating_sub(1) as isize + added as isize - removed as isize;
+            }
+            None => failed.push(format!("hunk {} ({}): context not found", index + 1, hunk.header)),
+        }
+    }
+
+    if !failed.is_empty() {
+        return Err(format!(
+            "Could not apply {} of {} hunks:\n  {}",
+            failed.len(),
+            hunks.len(),
+            failed.join("\n  ")
+        )
+        .into());
+    }
+
+    // Only the last line may lack a line ending, and only if it did before
+    let count = lines.len();
+    for (index, line) in lines.iter_mut().enumerate() {
+        let ends = line.ends_with('\n');
+        if (index + 1 < count || content.ends_with('\n')) && !ends {
+            line.push_str(ending);
+        } else if index + 1 == count && !content.ends_with('\n') && ends {
+            line.truncate(without_ending(line).len());
+        }
+    }
+    Ok(lines.concat())
+}
+
+// `line` without its `\n` or `\r\n`
+fn without_ending(line: &str) -> &str {
+    let line = line.strip_suffix('\n').unwrap_or(line);
+    line.strip_suffix('\r').unwrap_or(line)
+}
+
+fn parse(diff: &str) -> Result<Vec<Hunk<'_>>, Box<dyn Error>> {
+    let mut hunks: Vec<Hunk> = Vec::new();
+
+    for line in diff.lines() {
+        if line.starts_with("@@") {
+            hunks.push(Hunk {
+                header: line,
+                old_start: parse_old_start(line)?,
+                lines: Vec::new(),
+            });
+            continue;
+        }
+        // Skip markdown fences, and file headers before the first hunk
+        if line.starts_with("```") {
+            continue;
+        }
+        let Some(hunk) = hunks.last_mut() else {
+            continue;
+        };
+
+        let parsed = match line.chars().next() {
+            Some(' ') => Line::Context(&line[1..]),
+            Some('-') => Line::Remove(&line[1..]),
+            Some('+') => Line::Add(&line[1..]),
+            Some('\\') => continue,
+            // Models often drop the leading space on blank context lines
+            None => Line::Context(""),
+            Some(_) => return Err(format!("Unexpected line in diff hunk: {}", line).into()),
+        };
+        hunk.lines.push(parsed);
+    }
+
+    // An empty diff is how the model says the file needs no change
+    if hunks.is_empty() && diff.lines().any(|line| !line.trim().is_empty() && !line.starts_with("```")) {
+        return Err("No hunks found in diff".into());
+    }
+    Ok(hunks)
+}
+
+fn parse_old_start(header: &str) -> Result<usize, Box<dyn Error>> {
+    header
+        .split_whitespace()
+        .find_map(|part| part.strip_prefix('-'))
+        .and_then(|range| range.split(',').next())
+        .and_then(|start| start.parse().ok())
+        .ok_or_else(|| format!("Invalid hunk header: {}", header).into())
+}
+
+/// Finds where `hunk` applies in `lines` at or after `floor`, preferring the
+/// match closest to `expected`. Returns the start index and the hunk lines
+/// that were matched (context may have been trimmed by fuzz).
+fn locate<'a>(lines: &[String], hunk: &Hunk<'a>, floor: usize, expected: usize) -> Option<(usize, Vec<Line<'a>>)> {
+    for fuzz in 0..=MAX_FUZZ {
+        let Some(trimmed) = trim_context(&hunk.lines, fuzz) else {
+            break;
+        };
+        let old: Vec<&str> = trimmed
+            .iter()
+            .filter_map(|line| match line {
+                Line::Context(text) | Line::Remove(text) => Some(*text),
+                Line::Add(_) => None,
+            })
+            .collect();
+
+        // Pure insertion without any context: trust the header
+        if old.is_empty() {
+            if fuzz > 0 {
+                break;
+            }
+            return Some((expected.clamp(floor, lines.len()), trimmed));
+        }
+        if old.len() > lines.len() {
+            continue;
+        }
+
+        for compare in [Compare::Exact, Compare::TrimEnd, Compare::Trim] {
+            let found = (floor..=lines.len() - old.len())
+                .filter(|&start| old.iter().zip(&lines[start..]).all(|(a, b)| compare.eq(a, b)))
+                .min_by_key(|&start| start.abs_diff(expected));
+            if let Some(start) = found {
+                return Some((start, trimmed));
+            }
+        }
+    }
+    None
+}
+
+/// Drops up to `fuzz` context lines from each end of the hunk. Returns `None`
+/// once there is no more context to drop.
+fn trim_context<'a>(lines: &[Line<'a>], fuzz: usize) -> Option<Vec<Line<'a>>> {
+    if fuzz == 0 {
+        return Some(lines.to_vec());
+    }
+    let leading = lines.iter().take_while(|line| matches!(line, Line::Context(_))).count();
+    let trailing = lines.iter().rev().take_while(|line| matches!(line, Line::Context(_))).count();
+    if leading == lines.len() || (leading < fuzz && trailing < fuzz) {
+        return None;
+    }
+    let front = leading.min(fuzz);
+    let back = trailing.min(fuzz);
+    Some(lines[front..lines.len() - back].to_vec())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn answer(diff: &str) -> String {
+        format!("<REASONING>r</REASONING>\n<DIFF>\n{}</DIFF>", diff)
+    }
+
+    #[test]
+    fn applies_hunks_with_shifted_line_numbers() {
+        let content = "a\nb\nc\nd\n";
+        let output = answer("@@ -5,3 +5,3 @@\n b\n-c\n+C\n d\n");
+        assert_eq!(apply(&output, content).unwrap(), "a\nb\nC\nd\n");
+    }
+
+    #[test]
+    fn keeps_crlf_line_endings() {
+        let output = answer("@@ -1,3 +1,3 @@\n a\n-b\n+B\n c\n");
+        assert_eq!(apply(&output, "a\r\nb\r\nc\r\n").unwrap(), "a\r\nB\r\nc\r\n");
+    }
+
+    #[test]
+    fn keeps_a_missing_final_line_break() {
+        let output = answer("@@ -1,2 +1,3 @@\n a\n b\n+c\n");
+        assert_eq!(apply(&output, "a\nb").unwrap(), "a\nb\nc");
+        assert_eq!(apply(&output, "a\nb\n").unwrap(), "a\nb\nc\n");
+    }
+
+    #[test]
+    fn empty_diff_leaves_the_file_unchanged() {
+        assert_eq!(apply(&answer(""), "a\r\n").unwrap(), "a\r\n");
+        assert!(apply(&answer("just some text\n"), "a\n").is_err());
+    }
+
+    #[test]
+    fn reports_hunks_that_do_not_apply() {
+        let error = apply(&answer("@@ -1,1 +1,1 @@\n-x\n+y\n"), "a\n").unwrap_err();
+        assert!(error.to_string().contains("Could not apply 1 of 1 hunks"));
+    }
+}
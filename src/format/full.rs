@@ -0,0 +1,55 @@
+use std::error::Error;
+
+use super::extract_tag;
+
+pub const SYSTEM_PROMPT: &str = "You are an expert code transformation assistant. Your task is to carefully refactor code based on the user's instruction and return only the modified file contents enclosed within <CHANGED_FILE_CONTENTS> tags. Additionally, provide your reasoning inside <REASONING> tags. Do not include any other text outside these tags.";
+
+pub const EXAMPLE_ANSWER: &str = "<CHANGED_FILE_CONTENTS>\nlet new_value = 10;\nlet new_name = \"example\";\nlet other_var = 5;\n</CHANGED_FILE_CONTENTS>";
+
+/// The file inside `<CHANGED_FILE_CONTENTS>`, without the line breaks that
+/// separate it from the tags. It ends with a line break if `content` does.
+pub fn apply(output: &str, content: &str) -> Result<String, Box<dyn Error>> {
+    let body = extract_tag(output, "CHANGED_FILE_CONTENTS")?;
+    let body = body.strip_prefix("\r\n").or_else(|| body.strip_prefix('\n')).unwrap_or(body);
+    let body = body.strip_suffix("\r\n").or_else(|| body.strip_suffix('\n')).unwrap_or(body);
+
+    let mut result = body.to_string();
+    // The file's own final line break is often taken for the one before the closing tag
+    if !result.is_empty() && !result.ends_with('\n') {
+        if content.ends_with("\r\n") {
+            result.push_str("\r\n");
+        } else if content.ends_with('\n') {
+            result.push('\n');
+        }
+    }
+    Ok(result)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn unchanged_answer_keeps_the_file_byte_for_byte() {
+        let content = "fn a() {\n    b();\n}\n";
+        let output = format!("<REASONING>none</REASONING>\n<CHANGED_FILE_CONTENTS>\n{}</CHANGED_FILE_CONTENTS>", content);
+        assert_eq!(apply(&output, content).unwrap(), content);
+        // Echoed the way the prompt shows it, with a line break after the file's own
+        let output = format!("<CHANGED_FILE_CONTENTS>\n{}\n</CHANGED_FILE_CONTENTS>", content);
+        assert_eq!(apply(&output, content).unwrap(), content);
+    }
+
+    #[test]
+    fn keeps_leading_indentation_and_missing_final_line_break() {
+        let output = "<CHANGED_FILE_CONTENTS>\n    indented\n</CHANGED_FILE_CONTENTS>";
+        assert_eq!(apply(output, "    indented").unwrap(), "    indented");
+        assert_eq!(apply(output, "    indented\n").unwrap(), "    indented\n");
+    }
+
+    #[test]
+    fn keeps_crlf_line_breaks() {
+        let content = "a\r\nb\r\n";
+        let output = "<CHANGED_FILE_CONTENTS>\r\na\r\nb\r\n</CHANGED_FILE_CONTENTS>";
+        assert_eq!(apply(output, content).unwrap(), content);
+    }
+}
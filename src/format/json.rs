@@ -0,0 +1,102 @@
+use std::error::Error;
+
+use serde::Deserialize;
+use serde_json::json;
+
+use super::search_replace::{self, Block};
+use super::Applied;
+
+pub const SYSTEM_PROMPT: &str = "You are an expert code transformation assistant. Your task is to carefully refactor code based on the user's instruction and answer with a single JSON object with these fields: `reasoning` (your reasoning), `changed` (false if the file needs no change), `new_contents` (the complete modified file, or null) and `edits` (a list of `{\"search\", \"replace\"}` objects where each `search` is copied exactly from the file and matches one place only, or null). Provide either `new_contents` or `edits`, not both. Do not include any other text outside the JSON object.";
+
+/// JSON schema the backend is asked to enforce through structured output or
+/// a forced tool call.
+pub fn schema() -> serde_json::Value {
+    json!({
+        "type": "object",
+        "properties": {
+            "reasoning": { "type": "string" },
+            "changed": { "type": "boolean" },
+            "new_contents": { "type": ["string", "null"] },
+            "edits": {
+                "type": ["array", "null"],
+                "items": {
+                    "type": "object",
+                    "properties": {
+                        "search": { "type": "string" },
+                        "replace": { "type": "string" }
+                    },
+                    "required": ["search", "replace"],
+                    "additionalProperties": false
+                }
+            }
+        },
+        "required": ["reasoning", "changed", "new_contents", "edits"],
+        "additionalProperties": false
+    })
+}
+
+pub fn example_answer(reasoning: &str) -> String {
+    // Written out by hand so `reasoning` comes first, as the model should produce it
+    format!(
+        "{{\"reasoning\":{},\"changed\":true,\"new_contents\":null,\"edits\":[{{\"search\":{},\"replace\":{}}}]}}",
+        json!(reasoning),
+        json!("let old_value = 10;\nlet old_name = \"example\";"),
+        json!("let new_value = 10;\nlet new_name = \"example\";")
+    )
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Answer {
+    // Required so the model thinks before editing; not used afterwards
+    #[allow(dead_code)]
+    reasoning: String,
+    changed: bool,
+    #[serde(default)]
+    new_contents: Option<String>,
+    #[serde(default)]
+    edits: Option<Vec<Edit>>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Edit {
+    search: String,
+    replace: String,
+}
+
+/// Validates the JSON answer and applies it to `content`. Malformed answers
+/// are rejected before anything is written.
+pub fn apply(output: &str, content: &str) -> Result<Applied, Box<dyn Error>> {
+    let answer: Answer = serde_json::from_str(strip_fences(output))
+        .map_err(|e| format!("Malformed JSON answer: {}", e))?;
+
+    if !answer.changed {
+        return Ok(Applied::Complete(content.to_string()));
+    }
+
+    match (answer.new_contents, answer.edits) {
+        (Some(new_contents), None) => Ok(Applied::Complete(new_contents)),
+        (None, Some(edits)) if !edits.is_empty() => {
+            let blocks: Vec<Block> = edits
+                .into_iter()
+                .map(|edit| Block { search: edit.search, replace: edit.replace })
+                .collect();
+            Ok(search_replace::apply_blocks(&blocks, content))
+        }
+        (Some(_), Some(_)) => Err("JSON answer has both `new_contents` and `edits`".into()),
+        _ => Err("JSON answer is marked as changed but has neither `new_contents` nor `edits`".into()),
+    }
+}
+
+// Backends without enforced structured output sometimes wrap JSON in markdown
+fn strip_fences(output: &str) -> &str {
+    let output = output.trim();
+    match output.strip_prefix("```") {
+        Some(rest) => {
+            let rest = rest.strip_prefix("json").unwrap_or(rest);
+            rest.strip_suffix("```").unwrap_or(rest).trim()
+        }
+        None => output,
+    }
+}
@@ -0,0 +1,127 @@
+use std::error::Error;
+
+use crate::provider::{ChatMessage, ResponseSchema};
+
+mod diff;
+mod full;
+mod json;
+mod search_replace;
+
+pub use search_replace::{apply_blocks, parse as parse_blocks, Block};
+
+/// Names accepted by `--output-format`.
+pub const FORMATS: &[&str] = &["full", "diff", "search-replace", "json"];
+
+/// How the model is asked to return its change, and how that answer is turned
+/// back into new file contents.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OutputFormat {
+    /// The whole file inside `<CHANGED_FILE_CONTENTS>` tags.
+    Full,
+    /// Unified-diff hunks inside `<DIFF>` tags.
+    Diff,
+    /// SEARCH/REPLACE blocks inside `<EDITS>` tags.
+    SearchReplace,
+    /// A JSON object enforced through the backend's structured output.
+    Json,
+}
+
+/// The result of applying a model answer to a file.
+#[derive(Debug)]
+pub enum Applied {
+    /// Every edit in the answer was applied.
+    Complete(String),
+    /// Some edits could not be applied. `content` has the rest applied and
+    /// `repair_prompt` asks the model to redo only the failed ones.
+    Partial { content: String, repair_prompt: String },
+}
+
+// Shared few-shot example, shown to the model in the requested format
+const EXAMPLE_INSTRUCTION: &str = "Replace all variable names that start with \"old_\" to start with \"new_\".";
+const EXAMPLE_FILE: &str = "let old_value = 10;\nlet old_name = \"example\";\nlet other_var = 5;";
+const EXAMPLE_REASONING: &str = "The instruction is to change all variable names that start with \"old_\" to \"new_\". This is a straightforward text transformation, so the variables old_value and old_name will be renamed to new_value and new_name, respectively. Variables that don't start with \"old_\" remain unchanged.";
+
+impl OutputFormat {
+    pub fn from_name(name: &str) -> Result<Self, Box<dyn Error>> {
+        match name {
+            "full" => Ok(OutputFormat::Full),
+            "diff" => Ok(OutputFormat::Diff),
+            "search-replace" => Ok(OutputFormat::SearchReplace),
+            "json" => Ok(OutputFormat::Json),
+            other => Err(format!("Unknown output format: {}", other).into()),
+        }
+    }
+
+    /// Builds the conversation for one attempt: system prompt, a worked
+    /// example in this format, then the real instruction and file, followed by
+    /// `feedback` on the previous attempt if there was one.
+    pub fn messages(&self, instruction: &str, content: &str, feedback: Option<&str>) -> Vec<ChatMessage> {
+        let tagged = |answer: &str| format!("<REASONING>\n{}\n</REASONING>\n\n{}", EXAMPLE_REASONING, answer);
+        let (system, example_answer) = match self {
+            OutputFormat::Full => (full::SYSTEM_PROMPT, tagged(full::EXAMPLE_ANSWER)),
+            OutputFormat::Diff => (diff::SYSTEM_PROMPT, tagged(diff::EXAMPLE_ANSWER)),
+            OutputFormat::SearchReplace => (search_replace::SYSTEM_PROMPT, tagged(search_replace::EXAMPLE_ANSWER)),
+            OutputFormat::Json => (json::SYSTEM_PROMPT, json::example_answer(EXAMPLE_REASONING)),
+        };
+
+        vec![
+            ChatMessage::system(system),
+            ChatMessage::user(user_prompt(EXAMPLE_INSTRUCTION, EXAMPLE_FILE, None)),
+            ChatMessage::assistant(example_answer),
+            ChatMessage::user(user_prompt(instruction, content, feedback)),
+        ]
+    }
+
+    /// Schema the backend should enforce on the answer, if this format has one.
+    pub fn response_schema(&self) -> Option<ResponseSchema> {
+        match self {
+            OutputFormat::Json => Some(ResponseSchema {
+                name: "refactoring_result".to_string(),
+                schema: json::schema(),
+            }),
+            _ => None,
+        }
+    }
+
+    /// The tag enclosing the answer, for formats whose answer can be continued
+    /// after being cut off. Structured JSON cannot be.
+    pub fn answer_tag(&self) -> Option<&'static str> {
+        match self {
+            OutputFormat::Full => Some("CHANGED_FILE_CONTENTS"),
+            OutputFormat::Diff => Some("DIFF"),
+            OutputFormat::SearchReplace => Some("EDITS"),
+            OutputFormat::Json => None,
+        }
+    }
+
+    /// Turns the model's answer into the new contents of the file.
+    pub fn apply(&self, output: &str, content: &str) -> Result<Applied, Box<dyn Error>> {
+        match self {
+            OutputFormat::Full => full::apply(output, content).map(Applied::Complete),
+            OutputFormat::Diff => diff::apply(output, content).map(Applied::Complete),
+            OutputFormat::SearchReplace => search_replace::apply(output, content),
+            OutputFormat::Json => json::apply(output, content),
+        }
+    }
+}
+
+fn user_prompt(instruction: &str, content: &str, feedback: Option<&str>) -> String {
+    let mut prompt = format!(
+        "<INSTRUCTION>\n{}\n</INSTRUCTION>\n\n<FILECONTENTS>\n{}\n</FILECONTENTS>",
+        instruction, content
+    );
+    if let Some(feedback) = feedback {
+        prompt.push_str("\n\n");
+        prompt.push_str(feedback);
+    }
+    prompt
+}
+
+/// Returns the text between `<tag>` and `</tag>`.
+pub fn extract_tag<'a>(output: &'a str, tag: &str) -> Result<&'a str, Box<dyn Error>> {
+    let start_tag = format!("<{}>", tag);
+    let end_tag = format!("</{}>", tag);
+    let start = output.find(&start_tag).ok_or("Start tag not found")? + start_tag.len();
+    let end = output[start..].find(&end_tag).ok_or("End tag not found")? + start;
+    Ok(&output[start..end])
+}
@@ -0,0 +1,180 @@
+use std::error::Error;
+
+use super::{extract_tag, Applied};
+
+pub const SYSTEM_PROMPT: &str = "You are an expert code transformation assistant. Your task is to carefully refactor code based on the user's instruction and return only your changes as SEARCH/REPLACE blocks enclosed within <EDITS> tags. Each block starts with a line `<<<<<<< SEARCH`, followed by lines copied exactly from the current file, a line `=======`, the lines that replace them, and a line `>>>>>>> REPLACE`. Every SEARCH section must match exactly one place in the file, so include enough surrounding lines to make it unique. If the file needs no change, answer with an empty <EDITS></EDITS>. Additionally, provide your reasoning inside <REASONING> tags. Do not include any other text outside these tags.";
+
+pub const EXAMPLE_ANSWER: &str = "<EDITS>\n<<<<<<< SEARCH\nlet old_value = 10;\nlet old_name = \"example\";\n=======\nlet new_value = 10;\nlet new_name = \"example\";\n>>>>>>> REPLACE\n</EDITS>";
+
+const SEARCH_MARKER: &str = "<<<<<<< SEARCH";
+const DIVIDER_MARKER: &str = "=======";
+const REPLACE_MARKER: &str = ">>>>>>> REPLACE";
+
+pub struct Block {
+    pub search: String,
+    pub replace: String,
+}
+
+/// Applies the SEARCH/REPLACE blocks in `output` to `content` in order; an
+/// empty `<EDITS>` leaves it unchanged. Blocks that do not match are
+/// collected into a repair prompt instead of failing the whole answer.
+pub fn apply(output: &str, content: &str) -> Result<Applied, Box<dyn Error>> {
+    let blocks = match extract_tag(output, "EDITS") {
+        Ok(edits) if edits.trim().is_empty() => return Ok(Applied::Complete(content.to_string())),
+        Ok(edits) => parse(edits)?,
+        // Tolerate answers that forget the surrounding tags
+        Err(_) => parse(output)?,
+    };
+    Ok(apply_blocks(&blocks, content))
+}
+
+/// Applies `blocks` to `content` in order, collecting the ones that do not
+/// match into a repair prompt.
+pub fn apply_blocks(blocks: &[Block], content: &str) -> Applied {
+    let mut content = content.to_string();
+    let mut failed = Vec::new();
+    for block in blocks {
+        match replace_unique(&content, &block.search, &block.replace) {
+            Ok(updated) => content = updated,
+            Err(reason) => failed.push((block, reason)),
+        }
+    }
+
+    if failed.is_empty() {
+        return Applied::Complete(content);
+    }
+
+    let mut repair_prompt = format!(
+        "{} of your {} SEARCH/REPLACE blocks could not be applied; the others have been applied already. Return corrected SEARCH/REPLACE blocks for the failed ones only, with SEARCH sections copied exactly from the current file below.\n",
+        failed.len(),
+        blocks.len()
+    );
+    for (block, reason) in &failed {
+        repair_prompt.push_str(&format!(
+            "\n<FAILED_BLOCK>\nReason: {}\n{}\n{}\n{}\n{}\n{}\n</FAILED_BLOCK>\n",
+            reason, SEARCH_MARKER, block.search, DIVIDER_MARKER, block.replace, REPLACE_MARKER
+        ));
+    }
+    repair_prompt.push_str(&format!("\n<FILECONTENTS>\n{}\n</FILECONTENTS>", content));
+
+    Applied::Partial { content, repair_prompt }
+}
+
+pub fn parse(edits: &str) -> Result<Vec<Block>, Box<dyn Error>> {
+    let mut blocks = Vec::new();
+    let mut lines = edits.lines();
+
+    while let Some(line) = lines.next() {
+        if line.trim_end() != SEARCH_MARKER {
+            continue;
+        }
+        let mut search = Vec::new();
+        let mut replace = Vec::new();
+        let mut in_replace = false;
+        let mut closed = false;
+        for line in lines.by_ref() {
+            match line.trim_end() {
+                DIVIDER_MARKER if !in_replace => in_replace = true,
+                REPLACE_MARKER if in_replace => {
+                    closed = true;
+                    break;
+                }
+                _ if in_replace => replace.push(line),
+                _ => search.push(line),
+            }
+        }
+        if !closed {
+            return Err(format!("SEARCH/REPLACE block {} is not terminated", blocks.len() + 1).into());
+        }
+        blocks.push(Block {
+            search: search.join("\n"),
+            replace: replace.join("\n"),
+        });
+    }
+
+    if blocks.is_empty() {
+        return Err("No SEARCH/REPLACE blocks found".into());
+    }
+    Ok(blocks)
+}
+
+/// Replaces the single occurrence of `search` in `content`, first exactly and
+/// then line by line ignoring surrounding whitespace.
+fn replace_unique(content: &str, search: &str, replace: &str) -> Result<String, String> {
+    if search.trim().is_empty() {
+        return if content.trim().is_empty() {
+            Ok(replace.to_string())
+        } else {
+            Err("SEARCH section is empty".to_string())
+        };
+    }
+
+    // Replacement lines get the file's line ending
+    let ending = if content.contains("\r\n") { "\r\n" } else { "\n" };
+    let replace = replace.replace('\n', ending);
+
+    match content.matches(search).count() {
+        1 => return Ok(content.replacen(search, &replace, 1)),
+        0 => {}
+        n => return Err(format!("SEARCH section matches {} places exactly; include more surrounding lines", n)),
+    }
+
+    // Lines keep their line endings so that untouched ones stay byte for byte
+    let lines: Vec<&str> = content.split_inclusive('\n').collect();
+    let search_lines: Vec<&str> = search.lines().collect();
+    if search_lines.len() > lines.len() {
+        return Err("SEARCH section does not match the file".to_string());
+    }
+    let matches: Vec<usize> = (0..=lines.len() - search_lines.len())
+        .filter(|&start| {
+            search_lines
+                .iter()
+                .zip(&lines[start..])
+                .all(|(a, b)| a.trim() == b.trim())
+        })
+        .collect();
+
+    match matches.as_slice() {
+        [start] => {
+            let end = start + search_lines.len();
+            let mut result = lines[..*start].concat();
+            for line in replace.lines() {
+                result.push_str(line);
+                result.push_str(ending);
+            }
+            // The match ran to the end of a file without a final line break
+            if !lines[end - 1].ends_with('\n') && result.ends_with(ending) {
+                result.truncate(result.len() - ending.len());
+            }
+            result.push_str(&lines[end..].concat());
+            Ok(result)
+        }
+        [] => Err("SEARCH section does not match the file".to_string()),
+        many => Err(format!(
+            "SEARCH section matches {} places after whitespace normalisation; include more surrounding lines",
+            many.len()
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn whitespace_normalised_match_keeps_crlf_line_endings() {
+        let content = "a\r\n    b\r\nc\r\n";
+        assert_eq!(replace_unique(content, "a\n  b", "A\nB").unwrap(), "A\r\nB\r\nc\r\n");
+    }
+
+    #[test]
+    fn exact_match_keeps_crlf_line_endings() {
+        assert_eq!(replace_unique("a\r\nb\r\n", "b", "x\ny").unwrap(), "a\r\nx\r\ny\r\n");
+    }
+
+    #[test]
+    fn keeps_a_missing_final_line_break() {
+        assert_eq!(replace_unique("a\n  b", "a\nb", "c").unwrap(), "c");
+        assert_eq!(replace_unique("a\n  b\n", "a\nb", "c").unwrap(), "c\n");
+    }
+}
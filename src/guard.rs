@@ -0,0 +1,271 @@
+use std::error::Error;
+use std::fmt;
+use std::sync::LazyLock;
+
+use regex::Regex;
+
+// Files shorter than this may legitimately shrink a lot
+const MIN_LINES_FOR_SHRINK_CHECK: usize = 20;
+// A candidate with fewer than this share of the original's lines is suspicious
+const MIN_KEPT_LINE_RATIO: f64 = 0.5;
+
+// A comment that stands in for code instead of containing it, such as
+// `// ... rest of the file unchanged ...` or `# existing code here`
+static ELISION_MARKER: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(
+        r"(?ix)
+        ^\s*(?://|\#|/\*|\*|<!--|--|;|\{/\*)\s*
+        (?:
+            \.\.\.|…
+            |(?:code|content|implementation)\s+(?:unchanged|omitted|elided|as\s+before)\b
+            |same\s+as\s+(?:before|above|original)\b
+        )",
+    )
+    .unwrap()
+});
+// Comments that only stand in for code when they also say it was left out:
+// `// existing code here`, but not `// Other functions call this helper`
+static ELISION_PHRASE: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(
+        r"(?ix)
+        ^\s*(?://|\#|/\*|\*|<!--|--|;|\{/\*)\s*
+        (?:
+            (?:the\s+)?rest\s+of\b
+            |(?:the\s+)?remaining\s+(?:code|content|methods|functions|lines|implementation)\b
+            |(?:previous|existing|other|original|unchanged)\s+(?:code|content|methods|functions|implementation|imports)\b
+        )",
+    )
+    .unwrap()
+});
+static ELISION_WORD: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"(?i)\b(?:unchanged|omitted|elided|as\s+before|here|not\s+shown)\b|\.\.\.|…").unwrap());
+
+// Shortest repeated text that is taken to be an overlap between two pieces of an answer
+const MIN_OVERLAP_CHARS: usize = 20;
+// Longest overlap looked for between two pieces
+const MAX_OVERLAP_CHARS: usize = 2000;
+
+/// Sent after a cut-off answer to get the rest of it.
+pub const CONTINUE_PROMPT: &str = "Your answer was cut off at the output token limit. Continue it exactly where it stopped, starting with the very next character. Do not repeat anything you already wrote, do not start over and do not add any explanation.";
+
+/// The answer stopped at the output token limit, so it is incomplete.
+#[derive(Debug)]
+pub struct Truncated;
+
+impl fmt::Display for Truncated {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("the answer was cut off at the output token limit")
+    }
+}
+
+impl Error for Truncated {}
+
+/// Signs that `candidate` does not contain the whole new file but leaves
+/// parts of `original` out: placeholder comments that were not in the
+/// original and, unless `allow_shrink` is set, losing most of its lines.
+pub fn check(original: &str, candidate: &str, allow_shrink: bool) -> Vec<String> {
+    let mut problems = Vec::new();
+
+    for (number, line) in candidate.lines().enumerate() {
+        if is_placeholder(line) && !original.lines().any(|old| old.trim() == line.trim()) {
+            problems.push(format!(
+                "line {} is a placeholder instead of code: `{}`. Write out every line of the file",
+                number + 1,
+                line.trim()
+            ));
+        }
+    }
+
+    let (before, after) = (original.lines().count(), candidate.lines().count());
+    if !allow_shrink && before >= MIN_LINES_FOR_SHRINK_CHECK && (after as f64) < before as f64 * MIN_KEPT_LINE_RATIO {
+        problems.push(format!(
+            "the file went from {} to {} lines. If that was not intended, output the complete file without leaving anything out",
+            before, after
+        ));
+    }
+
+    problems
+}
+
+fn is_placeholder(line: &str) -> bool {
+    ELISION_MARKER.is_match(line) || (ELISION_PHRASE.is_match(line) && ELISION_WORD.is_match(line))
+}
+
+/// Tells the model why its previous answer was not written.
+pub fn feedback_prompt(problems: &[String]) -> String {
+    let mut prompt = String::from(
+        "<PREVIOUS_ATTEMPT>\nYour previous answer was not used because it looks incomplete:\n",
+    );
+    for problem in problems {
+        prompt.push_str(&format!("- {}\n", problem));
+    }
+    prompt.push_str("Answer again, following the instruction, and make sure nothing is elided or cut short.\n</PREVIOUS_ATTEMPT>");
+    prompt
+}
+
+/// `feedback_prompt` for an answer that hit the output token limit.
+pub fn truncated_prompt() -> String {
+    feedback_prompt(&[format!(
+        "{}. Keep the reasoning short so that the whole answer fits",
+        Truncated
+    )])
+}
+
+/// `feedback_prompt` for an answer that could not be used at all, such as
+/// one with malformed JSON or a missing tag.
+pub fn unusable_prompt(error: &dyn Error) -> String {
+    format!(
+        "<PREVIOUS_ATTEMPT>\nYour previous answer was not used because it could not be applied: {}\nAnswer again, following the instruction and the answer format exactly.\n</PREVIOUS_ATTEMPT>",
+        error
+    )
+}
+
+/// Appends `continuation` to the cut-off answer `partial`, dropping text the
+/// model repeated from the end of `partial`. Fails if the continuation starts
+/// the answer over instead of continuing it.
+pub fn stitch(partial: &str, continuation: &str, tag: &str) -> Result<String, Box<dyn Error>> {
+    let mut continuation = continuation;
+    // A continuation wrapped in a code fence of its own
+    if continuation.trim_start().starts_with("```") && !partial.contains("```") {
+        let fenced = continuation.trim_start();
+        continuation = fenced.split_once('\n').map_or("", |(_, rest)| rest);
+        if let Some(end) = continuation.rfind("```") {
+            continuation = &continuation[..end];
+        }
+    }
+    if continuation.contains(&format!("<{}>", tag)) {
+        return Err("The continuation started the answer over instead of continuing it".into());
+    }
+
+    // The model repeated the end of what it already wrote
+    let longest = partial.len().min(continuation.len()).min(MAX_OVERLAP_CHARS);
+    if let Some(overlap) = (MIN_OVERLAP_CHARS..=longest)
+        .rev()
+        .find(|&length| continuation.is_char_boundary(length) && partial.ends_with(&continuation[..length]))
+    {
+        return Ok(format!("{}{}", partial, &continuation[overlap..]));
+    }
+
+    // The model rewrote the line it was in the middle of
+    let (complete, unfinished) = partial.rsplit_once('\n').unwrap_or(("", partial));
+    let first_line = continuation.lines().next().unwrap_or("");
+    if !unfinished.trim().is_empty() && first_line.len() > unfinished.len() && first_line.starts_with(unfinished) {
+        return Ok(format!("{}\n{}", complete, continuation));
+    }
+
+    Ok(format!("{}{}", partial, continuation))
+}
+
+/// Checks that an answer stitched together from several pieces opens and
+/// closes `tag` exactly once, in that order.
+pub fn check_stitched(output: &str, tag: &str) -> Result<(), Box<dyn Error>> {
+    let (open, close) = (format!("<{}>", tag), format!("</{}>", tag));
+    let (opens, closes) = (output.matches(&open).count(), output.matches(&close).count());
+    if opens != 1 || closes != 1 || output.find(&open) > output.find(&close) {
+        return Err(format!(
+            "The continued answer is not coherent: found {} {} and {} {} tags",
+            opens, open, closes, close
+        )
+        .into());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn flags_placeholder_comments() {
+        for line in [
+            "// ...",
+            "    # … rest of the file unchanged …",
+            "// rest of the file unchanged",
+            "# existing code here",
+            "/* previous methods as before */",
+            "  * other functions omitted",
+            "-- remaining lines not shown",
+            "<!-- ... -->",
+            "// implementation unchanged",
+            "; same as before",
+            "{/* the rest of the component here */}",
+        ] {
+            assert!(is_placeholder(line), "not flagged: {}", line);
+        }
+    }
+
+    #[test]
+    fn accepts_ordinary_comments() {
+        for line in [
+            "// Other functions call this helper",
+            " * Other methods are documented below",
+            "-- remaining lines are ignored by the parser",
+            "// The rest of the loop handles errors",
+            "# Existing code paths rely on this order",
+            "let rest = remaining_code(); // not a comment opener at the start",
+            "// Returns the remaining content",
+        ] {
+            assert!(!is_placeholder(line), "flagged: {}", line);
+        }
+    }
+
+    #[test]
+    fn placeholders_already_in_the_original_are_kept() {
+        let original = "fn a() {}\n// ...\n";
+        assert!(check(original, "fn b() {}\n// ...\n", false).is_empty());
+        assert_eq!(check("fn a() {}\n", "// existing code here\n", false).len(), 1);
+    }
+
+    const TAG: &str = "CHANGED_FILE_CONTENTS";
+
+    #[test]
+    fn stitch_appends_a_plain_continuation() {
+        let partial = "<CHANGED_FILE_CONTENTS>\nfn main() {\n    let va";
+        let stitched = stitch(partial, "lue = 1;\n}\n</CHANGED_FILE_CONTENTS>", TAG).unwrap();
+        assert_eq!(stitched, "<CHANGED_FILE_CONTENTS>\nfn main() {\n    let value = 1;\n}\n</CHANGED_FILE_CONTENTS>");
+        check_stitched(&stitched, TAG).unwrap();
+    }
+
+    #[test]
+    fn stitch_drops_text_repeated_from_the_end() {
+        let partial = "<CHANGED_FILE_CONTENTS>\nfn main() {\n    let value = compute();\n";
+        let continuation = "    let value = compute();\n    println!(\"{}\", value);\n}\n</CHANGED_FILE_CONTENTS>";
+        assert_eq!(
+            stitch(partial, continuation, TAG).unwrap(),
+            "<CHANGED_FILE_CONTENTS>\nfn main() {\n    let value = compute();\n    println!(\"{}\", value);\n}\n</CHANGED_FILE_CONTENTS>"
+        );
+    }
+
+    #[test]
+    fn stitch_replaces_a_rewritten_unfinished_line() {
+        let partial = "<CHANGED_FILE_CONTENTS>\nfn main() {\n    let val";
+        let continuation = "    let value = 1;\n}\n</CHANGED_FILE_CONTENTS>";
+        assert_eq!(
+            stitch(partial, continuation, TAG).unwrap(),
+            "<CHANGED_FILE_CONTENTS>\nfn main() {\n    let value = 1;\n}\n</CHANGED_FILE_CONTENTS>"
+        );
+    }
+
+    #[test]
+    fn stitch_unwraps_a_fenced_continuation() {
+        let partial = "<CHANGED_FILE_CONTENTS>\nfn main() {\n";
+        let continuation = "```rust\n}\n</CHANGED_FILE_CONTENTS>\n```";
+        assert_eq!(stitch(partial, continuation, TAG).unwrap(), "<CHANGED_FILE_CONTENTS>\nfn main() {\n}\n</CHANGED_FILE_CONTENTS>\n");
+    }
+
+    #[test]
+    fn stitch_handles_multibyte_text() {
+        let partial = "<CHANGED_FILE_CONTENTS>\nlet greeting = \"grüße";
+        let continuation = "äöü € — 你好\";\n</CHANGED_FILE_CONTENTS>";
+        assert_eq!(stitch(partial, continuation, TAG).unwrap(), format!("{}{}", partial, continuation));
+    }
+
+    #[test]
+    fn rejects_an_answer_that_starts_over() {
+        let partial = "<REASONING>rename</REASONING>\n<CHANGED_FILE_CONTENTS>\nfn main() {\n";
+        assert!(stitch(partial, "<REASONING>again</REASONING>\n<CHANGED_FILE_CONTENTS>\nfn main() {}\n</CHANGED_FILE_CONTENTS>", TAG).is_err());
+        // Pieces that each look fine can still add up to two answers
+        assert!(check_stitched("<CHANGED_FILE_CONTENTS>\na\n", TAG).is_err());
+        assert!(check_stitched("</CHANGED_FILE_CONTENTS><CHANGED_FILE_CONTENTS>", TAG).is_err());
+    }
+}
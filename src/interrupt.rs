@@ -0,0 +1,69 @@
+use std::error::Error;
+use std::fmt;
+use std::panic;
+use std::process;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::Duration;
+
+use tokio::signal::unix::{signal, SignalKind};
+
+/// Exit code of a run stopped by Ctrl-C, as for a shell killed by SIGINT.
+pub const EXIT_CODE: i32 = 130;
+
+// How often `wait` and `blocking` look at the flag
+const POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+
+/// The user pressed Ctrl-C.
+#[derive(Debug)]
+pub struct Interrupted;
+
+impl fmt::Display for Interrupted {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("interrupted")
+    }
+}
+
+impl Error for Interrupted {}
+
+/// Handles Ctrl-C from now on: the first one asks the run to stop and clean
+/// up, a second one exits at once.
+pub fn install() {
+    let Ok(mut signals) = signal(SignalKind::interrupt()) else {
+        return;
+    };
+    tokio::spawn(async move {
+        signals.recv().await;
+        INTERRUPTED.store(true, Ordering::SeqCst);
+        eprintln!("\nInterrupted, cleaning up (press Ctrl-C again to exit at once)");
+        signals.recv().await;
+        eprintln!("Interrupted again, exiting without cleaning up");
+        process::exit(EXIT_CODE);
+    });
+}
+
+pub fn interrupted() -> bool {
+    INTERRUPTED.load(Ordering::SeqCst)
+}
+
+/// Runs `work` on a thread of its own and waits for it, giving up as soon as
+/// the user presses Ctrl-C. Abandoned work finishes or dies with the process.
+pub fn blocking<T: Send + 'static>(work: impl FnOnce() -> T + Send + 'static) -> Result<T, Interrupted> {
+    let worker = thread::spawn(work);
+    while !worker.is_finished() {
+        if interrupted() {
+            return Err(Interrupted);
+        }
+        thread::sleep(POLL_INTERVAL);
+    }
+    worker.join().map_err(|panic| panic::resume_unwind(panic))
+}
+
+/// Completes once the user pressed Ctrl-C.
+pub async fn wait() {
+    while !interrupted() {
+        tokio::time::sleep(POLL_INTERVAL).await;
+    }
+}
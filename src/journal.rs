@@ -0,0 +1,242 @@
+use std::cell::RefCell;
+use std::error::Error;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::multi_file;
+use crate::workspace;
+
+/// Directory, relative to where the tool runs, in which it keeps its own files.
+pub const STATE_DIR: &str = ".refactoring-assistant";
+
+const MANIFEST: &str = "journal.json";
+
+/// What a run did to one file. Absent content means the file did not exist.
+#[derive(Serialize, Deserialize)]
+struct Entry {
+    path: PathBuf,
+    original_sha256: Option<String>,
+    final_sha256: Option<String>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Manifest {
+    id: String,
+    /// Seconds since the Unix epoch.
+    started: u64,
+    instruction: String,
+    model: String,
+    /// Every file the run wrote; the content of entry `n` is kept in
+    /// `originals/n` and `finals/n`.
+    files: Vec<Entry>,
+    undone: bool,
+}
+
+/// Records the original and final content of every file a run writes, under
+/// `.refactoring-assistant/runs/<id>/`, so the run can be undone later.
+pub struct Journal {
+    dir: PathBuf,
+    manifest: RefCell<Manifest>,
+}
+
+impl Journal {
+    /// Starts the journal of a new run. Nothing is written until the run
+    /// changes a file.
+    pub fn new(instruction: &str, model: &str) -> Self {
+        let started = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |elapsed| elapsed.as_secs());
+        let mut id = timestamp(started);
+        // Runs started in the same second
+        let mut count = 1;
+        while runs_dir().join(&id).exists() {
+            count += 1;
+            id = format!("{}-{}", timestamp(started), count);
+        }
+        Journal {
+            dir: runs_dir().join(&id),
+            manifest: RefCell::new(Manifest {
+                id,
+                started,
+                instruction: instruction.to_string(),
+                model: model.to_string(),
+                files: Vec::new(),
+                undone: false,
+            }),
+        }
+    }
+
+    /// Records that `path` went from `before` to `after`. A file written more
+    /// than once keeps the content it had before the first write.
+    pub fn record(&self, path: &Path, before: Option<&str>, after: Option<&str>) -> Result<(), Box<dyn Error>> {
+        let mut manifest = self.manifest.borrow_mut();
+        let index = match manifest.files.iter().position(|entry| entry.path == path) {
+            Some(index) => index,
+            None => {
+                let index = manifest.files.len();
+                multi_file::write_state(&self.dir.join("originals").join(index.to_string()), before)?;
+                manifest.files.push(Entry {
+                    path: path.to_path_buf(),
+                    original_sha256: before.map(sha256),
+                    final_sha256: None,
+                });
+                index
+            }
+        };
+        multi_file::write_state(&self.dir.join("finals").join(index.to_string()), after)?;
+        manifest.files[index].final_sha256 = after.map(sha256);
+        save(&self.dir, &manifest)
+    }
+
+    /// Tells the user how to undo the run, if it changed anything.
+    pub fn finish(&self) {
+        let manifest = self.manifest.borrow();
+        if !manifest.files.is_empty() {
+            println!(
+                "Run {} changed {} files; undo it with `refactoring-assistant undo {}`",
+                manifest.id,
+                manifest.files.len(),
+                manifest.id
+            );
+        }
+    }
+}
+
+/// Puts back the original content of every file run `id` wrote. Files
+/// modified since the run make it refuse, unless `merge` is set: then the
+/// run's change is taken out of them with a three-way merge, and it still
+/// refuses if that conflicts. Nothing is written unless every file can be
+/// restored.
+pub fn undo(id: &str, merge: bool) -> Result<(), Box<dyn Error>> {
+    let dir = runs_dir().join(id);
+    let Ok(manifest) = fs::read_to_string(dir.join(MANIFEST)) else {
+        let mut runs: Vec<String> = fs::read_dir(runs_dir())
+            .map(|entries| entries.filter_map(|entry| Some(entry.ok()?.file_name().to_string_lossy().into_owned())).collect())
+            .unwrap_or_default();
+        runs.sort();
+        return Err(format!("No run {} in {}; known runs: {}", id, runs_dir().display(), runs.join(", ")).into());
+    };
+    let mut manifest: Manifest = serde_json::from_str(&manifest)?;
+    if manifest.undone {
+        return Err(format!("Run {} was already undone", id).into());
+    }
+
+    let read = |kind: &str, index: usize, hash: &Option<String>| -> Result<Option<String>, Box<dyn Error>> {
+        match hash {
+            Some(_) => Ok(Some(fs::read_to_string(dir.join(kind).join(index.to_string()))?)),
+            None => Ok(None),
+        }
+    };
+    let mut restores = Vec::new();
+    let mut modified = Vec::new();
+    let mut conflicts = Vec::new();
+    for (index, entry) in manifest.files.iter().enumerate() {
+        let original = read("originals", index, &entry.original_sha256)?;
+        let current = fs::read_to_string(&entry.path).ok();
+        if current.as_deref().map(sha256) == entry.final_sha256 {
+            restores.push((&entry.path, original, "Restored"));
+            continue;
+        }
+        if !merge {
+            modified.push(entry.path.display().to_string());
+            continue;
+        }
+        let last = read("finals", index, &entry.final_sha256)?;
+        match (current, last, original) {
+            (Some(current), Some(last), Some(original)) => match merge_file(&current, &last, &original)? {
+                Some(merged) => restores.push((&entry.path, Some(merged), "Merged")),
+                None => conflicts.push(entry.path.display().to_string()),
+            },
+            // A file missing before the run, after it or now has nothing to merge with
+            _ => conflicts.push(entry.path.display().to_string()),
+        }
+    }
+    if !modified.is_empty() {
+        return Err(format!(
+            "Not undoing run {}: modified since the run: {}. Use `undo --merge` to take the run's change out of them",
+            id,
+            modified.join(", ")
+        )
+        .into());
+    }
+    if !conflicts.is_empty() {
+        return Err(format!("Not undoing run {}: the run's change cannot be taken out of {} without conflicts", id, conflicts.join(", ")).into());
+    }
+
+    for (path, content, verb) in restores {
+        multi_file::write_state(path, content.as_deref())?;
+        println!("{} {}", verb, path.display());
+    }
+    manifest.undone = true;
+    save(&dir, &manifest)?;
+    println!("Undid run {}", id);
+    Ok(())
+}
+
+fn runs_dir() -> PathBuf {
+    Path::new(STATE_DIR).join("runs")
+}
+
+fn save(dir: &Path, manifest: &Manifest) -> Result<(), Box<dyn Error>> {
+    multi_file::write_atomically(&dir.join(MANIFEST), &serde_json::to_string_pretty(manifest)?)
+}
+
+/// Hex SHA-256 of `content`.
+pub fn sha256(content: &str) -> String {
+    Sha256::digest(content.as_bytes()).iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+// Applies the change from `base` to `other` on top of `current` with `git
+// merge-file`. `None` if they conflict.
+fn merge_file(current: &str, base: &str, other: &str) -> Result<Option<String>, Box<dyn Error>> {
+    let dir = std::env::temp_dir().join(format!("{}merge-{}", workspace::TEMP_PREFIX, std::process::id()));
+    fs::create_dir_all(&dir)?;
+    let files = [("current", current), ("base", base), ("other", other)];
+    for (name, content) in files {
+        fs::write(dir.join(name), content)?;
+    }
+    let output = Command::new("git")
+        .arg("merge-file")
+        .arg("-p")
+        .args(files.map(|(name, _)| dir.join(name)))
+        .output();
+    fs::remove_dir_all(&dir)?;
+    let output = output?;
+    match output.status.code() {
+        Some(0) => Ok(Some(String::from_utf8(output.stdout)?)),
+        // The exit code is the number of conflicts, capped at 127; errors exit with 255
+        Some(1..=127) => Ok(None),
+        code => Err(format!(
+            "git merge-file failed ({}): {}",
+            code.map_or("killed by a signal".to_string(), |code| format!("exit code {}", code)),
+            String::from_utf8_lossy(&output.stderr).trim()
+        )
+        .into()),
+    }
+}
+
+// `YYYYMMDD-HHMMSS` in UTC, from Howard Hinnant's `civil_from_days`
+fn timestamp(seconds: u64) -> String {
+    let (days, time) = ((seconds / 86400) as i64, seconds % 86400);
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let day_of_era = z.rem_euclid(146097);
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let shifted_month = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * shifted_month + 2) / 5 + 1;
+    let month = if shifted_month < 10 { shifted_month + 3 } else { shifted_month - 9 };
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+    format!(
+        "{:04}{:02}{:02}-{:02}{:02}{:02}",
+        year,
+        month,
+        day,
+        time / 3600,
+        time % 3600 / 60,
+        time % 60
+    )
+}
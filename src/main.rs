@@ -1,14 +1,43 @@
-use std::env;
+use std::cell::RefCell;
+use std::collections::HashMap;
 use std::error::Error;
 use std::fs;
 use std::path::Path;
-use std::process::Command as ProcessCommand;
+use std::process;
+use std::time::Duration;
 
-use clap::{Arg, Command};
+use clap::{Arg, ArgAction, ArgMatches, Command};
 use glob::glob;
-use reqwest::Client;
-use serde_json::json;
-use tokio;
+
+use assertions::Assertions;
+use diagnostics::DiagnosticsFormat;
+use dry_run::DryRun;
+use format::OutputFormat;
+use interrupt::Interrupted;
+use journal::Journal;
+use progress::{FileStatus, Progress};
+use provider::{Provider, ProviderConfig};
+use refactor::{BaselineMode, RetryStrategy, RunConfig};
+use validation::{Equivalence, ValidationLimits};
+use validators::Validators;
+use workspace::{IsolationMode, Scratch};
+
+mod assertions;
+mod diagnostics;
+mod dry_run;
+mod format;
+mod guard;
+mod interrupt;
+mod journal;
+mod multi_file;
+mod progress;
+mod provider;
+mod refactor;
+mod review;
+mod test_results;
+mod validation;
+mod validators;
+mod workspace;
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn Error>> {
@@ -16,7 +45,24 @@ async fn main() -> Result<(), Box<dyn Error>> {
     let matches = Command::new("Refactoring Assistant")
         .version("1.1")
         .author("Author")
-        .about("Applies changes to files based on instructions using OpenAI GPT API and validates them")
+        .about("Applies changes to files based on instructions using an LLM and validates them")
+        .subcommand_negates_reqs(true)
+        .subcommand(
+            Command::new("undo")
+                .about("Restores the files changed by a previous run")
+                .arg(
+                    Arg::new("run_id")
+                        .value_name("RUN_ID")
+                        .help("Run to undo, as printed at the end of the run")
+                        .required(true)
+                )
+                .arg(
+                    Arg::new("merge")
+                        .long("merge")
+                        .help("Take the run's change out of files modified since the run with a three-way merge")
+                        .action(ArgAction::SetTrue)
+                )
+        )
         .arg(
             Arg::new("instruction")
                 .short('i')
@@ -38,17 +84,171 @@ async fn main() -> Result<(), Box<dyn Error>> {
                 .short('m')
                 .long("model")
                 .value_name("MODEL")
-                .help("OpenAI model to use for the change")
-                .default_value("gpt-4")
+                .help("Model to use for the change (defaults to the provider's default model)")
+        )
+        .arg(
+            Arg::new("provider")
+                .long("provider")
+                .value_name("PROVIDER")
+                .help("LLM backend to send requests to")
+                .value_parser(provider::PROVIDERS.to_vec())
+                .default_value("openai")
+        )
+        .arg(
+            Arg::new("base_url")
+                .long("base-url")
+                .value_name("URL")
+                .help("API root of an OpenAI-compatible server (e.g. http://localhost:8000/v1)")
+        )
+        .arg(
+            Arg::new("header")
+                .long("header")
+                .value_name("NAME: VALUE")
+                .help("Extra HTTP header to send with every request (can be repeated)")
+                .action(ArgAction::Append)
+        )
+        .arg(
+            Arg::new("no_auth")
+                .long("no-auth")
+                .help("Do not read an API key or send an Authorization header")
+                .action(ArgAction::SetTrue)
+        )
+        .arg(
+            Arg::new("pull")
+                .long("pull")
+                .help("Pull the model first if the local backend (ollama) does not have it")
+                .action(ArgAction::SetTrue)
+        )
+        .arg(
+            Arg::new("output_format")
+                .long("output-format")
+                .value_name("FORMAT")
+                .help("How the model returns its change: the full file, unified-diff hunks, SEARCH/REPLACE blocks or schema-checked JSON")
+                .value_parser(format::FORMATS.to_vec())
+                .default_value("full")
+        )
+        .arg(
+            Arg::new("full_fallback")
+                .long("full-fallback")
+                .help("Ask for a full rewrite when the model's edits cannot be applied")
+                .action(ArgAction::SetTrue)
+        )
+        .arg(
+            Arg::new("multi_file")
+                .long("multi-file")
+                .help("Send all matching files in one request and let the model edit, create, delete and rename files")
+                .action(ArgAction::SetTrue)
+        )
+        .arg(
+            Arg::new("bisect")
+                .long("bisect")
+                .help("Validate all changed files together and bisect a failure down to the files that cause it")
+                .action(ArgAction::SetTrue)
+                .conflicts_with("multi_file")
+        )
+        .arg(
+            Arg::new("assert_absent")
+                .long("assert-absent")
+                .value_name("REGEX")
+                .help("Pattern that must not match any file after the change (can be repeated)")
+                .action(ArgAction::Append)
+        )
+        .arg(
+            Arg::new("assert_present")
+                .long("assert-present")
+                .value_name("REGEX")
+                .help("Pattern that must match every changed file after the change (can be repeated)")
+                .action(ArgAction::Append)
+        )
+        .arg(
+            Arg::new("max_changed_lines")
+                .long("max-changed-lines")
+                .value_name("N")
+                .help("Most lines the change may add or remove in one file")
+                .value_parser(clap::value_parser!(usize))
+        )
+        .arg(
+            Arg::new("max_continuations")
+                .long("max-continuations")
+                .value_name("N")
+                .help("How many times to ask for the rest of an answer cut off at the output token limit")
+                .value_parser(clap::value_parser!(usize))
+                .default_value("3")
+        )
+        .arg(
+            Arg::new("allow_shrink")
+                .long("allow-shrink")
+                .help("Accept changes that remove more than half of a file's lines")
+                .action(ArgAction::SetTrue)
+        )
+        .arg(
+            Arg::new("dry_run")
+                .long("dry-run")
+                .help("Show the changes as colored diffs without writing any file; validation, if any, runs in a scratch copy")
+                .action(ArgAction::SetTrue)
+        )
+        .arg(
+            Arg::new("resume")
+                .long("resume")
+                .help("Only process the files that an earlier run of the same instruction and model did not finish")
+                .action(ArgAction::SetTrue)
+        )
+        .arg(
+            Arg::new("interactive")
+                .long("interactive")
+                .help("Review every proposed change hunk by hunk before it is written or validated")
+                .action(ArgAction::SetTrue)
+                .conflicts_with("bisect")
         )
         .arg(
             Arg::new("validate_with")
                 .short('v')
                 .long("validate-with")
                 .value_name("VALIDATION_COMMAND")
-                .help("Command to validate the change (e.g. `cargo build`)")
+                .help("Command to validate the change (e.g. `cargo build` or `ruff check {file}`)")
                 .required(false)
         )
+        .arg(
+            Arg::new("validators")
+                .long("validators")
+                .value_name("FILE")
+                .help("File of `PATTERN = COMMAND` lines choosing the validation command per file")
+        )
+        .arg(
+            Arg::new("diagnostics_format")
+                .long("diagnostics-format")
+                .value_name("FORMAT")
+                .help("How to parse the validation command's output into errors and warnings")
+                .value_parser(diagnostics::DIAGNOSTICS_FORMATS.to_vec())
+                .default_value("auto")
+        )
+        .arg(
+            Arg::new("equivalence_tests")
+                .long("equivalence-tests")
+                .value_name("TEST_COMMAND")
+                .help("Test command printing JUnit XML or TAP; candidates must leave every test's outcome unchanged")
+        )
+        .arg(
+            Arg::new("validation_timeout")
+                .long("validation-timeout")
+                .value_name("SECONDS")
+                .help("Kill the validation command and everything it started after this many seconds")
+                .value_parser(clap::value_parser!(u64))
+        )
+        .arg(
+            Arg::new("validation_cpu_limit")
+                .long("validation-cpu-limit")
+                .value_name("SECONDS")
+                .help("CPU time limit for each process of the validation command")
+                .value_parser(clap::value_parser!(u64))
+        )
+        .arg(
+            Arg::new("validation_memory_limit")
+                .long("validation-memory-limit")
+                .value_name("MEGABYTES")
+                .help("Address space limit for each process of the validation command")
+                .value_parser(clap::value_parser!(u64))
+        )
         .arg(
             Arg::new("n_retries")
                 .short('r')
@@ -57,13 +257,38 @@ async fn main() -> Result<(), Box<dyn Error>> {
                 .help("Number of retries for validation")
                 .default_value("5")
         )
+        .arg(
+            Arg::new("retry_strategy")
+                .long("retry-strategy")
+                .value_name("STRATEGY")
+                .help("How retries are produced: from the original file, by repairing the previous attempt, or best of all attempts")
+                .value_parser(refactor::RETRY_STRATEGIES.to_vec())
+                .default_value("fresh")
+        )
+        .arg(
+            Arg::new("baseline")
+                .long("baseline")
+                .value_name("MODE")
+                .help("Run the validation command once before any change; if it already fails, abort or only judge candidates on new failures")
+                .value_parser(refactor::BASELINE_MODES.to_vec())
+                .default_value("off")
+        )
+        .arg(
+            Arg::new("isolate")
+                .long("isolate")
+                .value_name("MODE")
+                .help("Validate candidates in a scratch copy or git worktree and only write files that pass")
+                .value_parser(workspace::ISOLATION_MODES.to_vec())
+                .default_value("none")
+        )
         .get_matches();
 
+    if let Some(("undo", undo)) = matches.subcommand() {
+        return journal::undo(undo.get_one::<String>("run_id").unwrap(), undo.get_flag("merge"));
+    }
+
     let instruction = matches.get_one::<String>("instruction").unwrap();
-    let file_pattern = matches.get_one::<String>("file_pattern").unwrap();
-    let default_model = "gpt-4".to_string();
-    let model = matches.get_one::<String>("model").unwrap_or(&default_model);
-    let validate_command = matches.get_one::<String>("validate_with");
+    let provider_name = matches.get_one::<String>("provider").unwrap();
     let n_retries: usize = matches
         .get_one::<String>("n_retries")
         .unwrap()
@@ -77,121 +302,168 @@ async fn main() -> Result<(), Box<dyn Error>> {
         instruction.clone()
     };
 
-    // Load OpenAI API key from environment
-    let api_key = env::var("OPENAI_API_KEY").expect("OPENAI_API_KEY must be set in the environment");
+    // Set up the LLM backend (reads its credentials from the environment)
+    let provider_config = ProviderConfig {
+        base_url: matches.get_one::<String>("base_url").cloned(),
+        headers: matches
+            .get_many::<String>("header")
+            .unwrap_or_default()
+            .map(|header| provider::parse_header(header))
+            .collect::<Result<_, _>>()?,
+        no_auth: matches.get_flag("no_auth"),
+        pull_missing_model: matches.get_flag("pull"),
+    };
+    let provider = provider::build_provider(provider_name, provider_config)?;
+    let default_model = provider.default_model().to_string();
+    let model = matches.get_one::<String>("model").unwrap_or(&default_model);
+    provider.check(model).await?;
+
+    let validators = Validators::new(
+        matches.get_one::<String>("validate_with").cloned(),
+        matches.get_one::<String>("validators").map(Path::new),
+    )?;
+    let equivalence_command = matches.get_one::<String>("equivalence_tests");
+    let dry_run = matches.get_flag("dry_run");
+    // Without validation there is nothing to run in the scratch copy
+    let isolation = match (&validators, equivalence_command) {
+        (None, None) => IsolationMode::None,
+        _ => match IsolationMode::from_name(matches.get_one::<String>("isolate").unwrap())? {
+            // A dry run never touches the real tree, not even to validate
+            IsolationMode::None if dry_run => IsolationMode::Copy,
+            mode => mode,
+        },
+    };
+
+    // From here on Ctrl-C stops the run cleanly instead of killing it
+    interrupt::install();
+    let journal = (!dry_run).then(|| Journal::new(&instruction_content, model));
+    let progress = if dry_run {
+        None
+    } else {
+        Some(Progress::load(&instruction_content, model, matches.get_flag("resume"))?)
+    };
+    let mut config = RunConfig {
+        instruction: instruction_content,
+        model: model.clone(),
+        output_format: OutputFormat::from_name(matches.get_one::<String>("output_format").unwrap())?,
+        full_fallback: matches.get_flag("full_fallback"),
+        validators,
+        diagnostics_format: DiagnosticsFormat::from_name(matches.get_one::<String>("diagnostics_format").unwrap())?,
+        validation_limits: ValidationLimits {
+            timeout: matches.get_one::<u64>("validation_timeout").map(|seconds| Duration::from_secs(*seconds)),
+            cpu_seconds: matches.get_one::<u64>("validation_cpu_limit").copied(),
+            memory_bytes: matches.get_one::<u64>("validation_memory_limit").map(|megabytes| megabytes * 1024 * 1024),
+        },
+        n_retries,
+        retry_strategy: RetryStrategy::from_name(matches.get_one::<String>("retry_strategy").unwrap())?,
+        scratch: Scratch::create(isolation)?,
+        baseline: HashMap::new(),
+        equivalence: None,
+        assertions: Assertions::new(
+            &matches.get_many::<String>("assert_absent").unwrap_or_default().cloned().collect::<Vec<_>>(),
+            &matches.get_many::<String>("assert_present").unwrap_or_default().cloned().collect::<Vec<_>>(),
+            matches.get_one::<usize>("max_changed_lines").copied(),
+        )?,
+        allow_shrink: matches.get_flag("allow_shrink"),
+        max_continuations: *matches.get_one::<usize>("max_continuations").unwrap(),
+        dry_run: dry_run.then(DryRun::new),
+        interactive: matches.get_flag("interactive"),
+        journal,
+        progress,
+        unaccepted: RefCell::default(),
+    };
+
+    // Dropping the unfinished run on Ctrl-C cancels its in-flight request
+    let result = tokio::select! {
+        result = run(&mut config, provider.as_ref(), &matches) => result,
+        () = interrupt::wait() => Err(Box::new(Interrupted) as Box<dyn Error>),
+    };
+    // However the run ended, no live file is left holding a candidate that was not accepted
+    config.restore_unaccepted()?;
+
+    if let Some(dry_run) = &config.dry_run {
+        dry_run.print_summary();
+    }
+    if let Some(journal) = &config.journal {
+        journal.finish();
+    }
+    if let Some(progress) = &config.progress {
+        progress.finish();
+    }
+
+    match result {
+        Err(e) if e.is::<Interrupted>() => {
+            // Exiting skips destructors, so the scratch copy is removed first
+            drop(config);
+            process::exit(interrupt::EXIT_CODE);
+        }
+        result => result,
+    }
+}
+
+// Processes every file matching the pattern
+async fn run(config: &mut RunConfig, provider: &dyn Provider, matches: &ArgMatches) -> Result<(), Box<dyn Error>> {
+    if let Some(command) = matches.get_one::<String>("equivalence_tests") {
+        config.equivalence = Some(Equivalence::record(command, config.scratch.as_ref().map(Scratch::workdir), &config.validation_limits)?);
+    }
 
     // Find files matching the given pattern
-    for entry in glob(file_pattern).expect("Failed to read glob pattern") {
+    let mut paths = Vec::new();
+    for entry in glob(matches.get_one::<String>("file_pattern").unwrap()).expect("Failed to read glob pattern") {
         match entry {
-            Ok(path) => {
-                if let Err(e) = process_file(&path, &instruction_content, model, &api_key, validate_command, n_retries).await {
-                    eprintln!("Error processing file {}: {}", path.display(), e);
-                }
-            }
+            // Never hand the tool's own journal, state or scratch files to the model
+            Ok(path) if workspace::is_own_file(&path) => {}
+            Ok(path) => paths.push(path),
             Err(e) => eprintln!("Error reading file pattern: {}", e),
         }
     }
 
-    Ok(())
-}
+    // Candidates for files outside the scratch copy could not be isolated
+    if let Some(scratch) = &config.scratch {
+        for path in &paths {
+            scratch.path_for(path)?;
+        }
+    }
+    if let Some(progress) = &config.progress {
+        paths = progress.start(paths)?;
+    }
 
-async fn process_file(
-    path: &Path,
-    instruction: &str,
-    model: &str,
-    api_key: &str,
-    validate_command: Option<&String>,
-    n_retries: usize,
-) -> Result<(), Box<dyn Error>> {
-    let original_content = fs::read_to_string(path)?;
-    let mut current_content = original_content.clone();
-    let client = Client::new();
-
-    // Retry mechanism
-    for attempt in 0..n_retries {
-        println!("Processing file {} (attempt {}/{})", path.display(), attempt + 1, n_retries);
-
-        // Improved system message to better reflect the task
-        let messages = vec![
-            json!({
-                "role": "system",
-                "content": "You are an expert code transformation assistant. Your task is to carefully refactor code based on the user's instruction and return only the modified file contents enclosed within <CHANGED_FILE_CONTENTS> tags. Additionally, provide your reasoning inside <REASONING> tags. Do not include any other text outside these tags."
-            }),
-            json!({
-                "role": "user",
-                "content": "<INSTRUCTION>\nReplace all variable names that start with \"old_\" to start with \"new_\".\n</INSTRUCTION>\n\n<FILECONTENTS>\nlet old_value = 10;\nlet old_name = \"example\";\nlet other_var = 5;\n</FILECONTENTS>"
-            }),
-            json!({
-                "role": "assistant",
-                "content": "<REASONING>\nThe instruction is to change all variable names that start with \"old_\" to \"new_\". This is a straightforward text transformation, so the variables old_value and old_name will be renamed to new_value and new_name, respectively. Variables that don't start with \"old_\" remain unchanged.\n</REASONING>\n\n<CHANGED_FILE_CONTENTS>\nlet new_value = 10;\nlet new_name = \"example\";\nlet other_var = 5;\n</CHANGED_FILE_CONTENTS>"
-            }),
-            json!({
-                "role": "user",
-                "content": format!(
-                    "<INSTRUCTION>\n{}\n</INSTRUCTION>\n\n<FILECONTENTS>\n{}\n</FILECONTENTS>",
-                    instruction, current_content
-                )
-            }),
-        ];
-
-        let request_body = json!({
-            "model": model,
-            "messages": messages
-        });
-
-        let response = client
-            .post("https://api.openai.com/v1/chat/completions")
-            .header("Authorization", format!("Bearer {}", api_key))
-            .json(&request_body)
-            .send()
-            .await?;
-
-        let response_json: serde_json::Value = response.json().await?;
-        let output = response_json["choices"][0]["message"]["content"]
-            .as_str()
-            .ok_or("Failed to parse the response")?;
-
-        // Extract content between <CHANGED_FILE_CONTENTS> tags
-        let start_tag = "<CHANGED_FILE_CONTENTS>";
-        let end_tag = "</CHANGED_FILE_CONTENTS>";
-        let start = output.find(start_tag).ok_or("Start tag not found")? + start_tag.len();
-        let end = output.find(end_tag).ok_or("End tag not found")?;
-        let transformed_content = &output[start..end].trim();
-
-        // Write the transformed content to the file
-        fs::write(path, transformed_content)?;
-
-        // Validate if the change was successful (if validation command is provided)
-        if let Some(command) = validate_command {
-            if validate_change(command)? {
-                println!("Changes applied and validated for {}", path.display());
-                return Ok(());
-            } else {
-                println!("Validation failed for {}, retrying...", path.display());
-                // Restore original content after final retry
-                if attempt == n_retries - 1 {
-                    fs::write(path, &original_content)?;
-                    println!("Restored original content for {}", path.display());
+    let multi_file = matches.get_flag("multi_file");
+    let bisect = matches.get_flag("bisect");
+    let mut baseline_mode = BaselineMode::from_name(matches.get_one::<String>("baseline").unwrap())?;
+    // Bisection blames whatever breaks a tree that passes without any change, so a
+    // failing tree stops the run before any request
+    if bisect && baseline_mode == BaselineMode::Off {
+        baseline_mode = BaselineMode::Abort;
+    }
+    // Batch modes validate all files with one combined command
+    config.baseline = refactor::run_baseline(config, baseline_mode, &paths, multi_file || bisect)?;
+    let config = &*config;
+
+    if bisect {
+        refactor::process_bisect(&paths, provider, config).await?;
+    } else if multi_file {
+        // Files the batch did not write needed no change
+        match refactor::process_batch(&paths, provider, config).await {
+            Ok(()) => config.settle(&paths, FileStatus::Skipped)?,
+            Err(e) if e.is::<Interrupted>() => return Err(e),
+            Err(e) => {
+                eprintln!("Error processing files: {}", e);
+                config.settle(&paths, FileStatus::Failed)?;
+            }
+        }
+    } else {
+        for path in &paths {
+            match refactor::process_file(path, provider, config).await {
+                Ok(()) => {}
+                Err(e) if e.is::<Interrupted>() => return Err(e),
+                Err(e) => {
+                    eprintln!("Error processing file {}: {}", path.display(), e);
+                    config.mark(path, FileStatus::Failed)?;
                 }
             }
-        } else {
-            // If no validation command, consider the changes successful
-            println!("Changes applied successfully for {}", path.display());
-            return Ok(());
         }
-
-        // Update current content for the next retry
-        current_content = fs::read_to_string(path)?;
     }
 
-    Err("Exceeded retry limit".into())
-}
-
-fn validate_change(command: &str) -> Result<bool, Box<dyn Error>> {
-    let status = ProcessCommand::new("sh")
-        .arg("-c")
-        .arg(command)
-        .status()?;
-
-    Ok(status.success())
+    Ok(())
 }
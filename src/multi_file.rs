@@ -0,0 +1,286 @@
+use std::collections::btree_map::Entry;
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::fs;
+use std::path::{Component, Path, PathBuf};
+
+use crate::format::{self, Applied, Block};
+use crate::provider::ChatMessage;
+use crate::validation;
+
+const SYSTEM_PROMPT: &str = "You are an expert code transformation assistant. Your task is to carefully refactor a set of files based on the user's instruction. You receive every file inside <FILE path=\"...\"> tags. Answer with one operation per affected file:\n- <WRITE path=\"...\">complete new contents</WRITE> to rewrite an existing file or create a new one,\n- <EDIT path=\"...\">SEARCH/REPLACE blocks</EDIT> to change part of an existing file, where each block is a line `<<<<<<< SEARCH`, lines copied exactly from the file, a line `=======`, the replacement lines and a line `>>>>>>> REPLACE`,\n- <DELETE path=\"...\" /> to remove a file,\n- <RENAME from=\"...\" to=\"...\" /> to rename or move a file.\nOperations are applied in order, so a file can be renamed and then edited under its new path. Use paths relative to the project root exactly as given. Files that need no change must not be mentioned, so if none needs one, answer without any operation. Additionally, provide your reasoning inside <REASONING> tags. Do not include any other text outside these tags.";
+
+const EXAMPLE_INSTRUCTION: &str = "Move the `greet` helper into its own module `greeting.py` and rename `app.py` to `main.py`.";
+const EXAMPLE_FILES: &[(&str, &str)] = &[(
+    "app.py",
+    "def greet(name):\n    return f\"Hello, {name}!\"\n\n\nprint(greet(\"world\"))\n",
+)];
+const EXAMPLE_ANSWER: &str = "<REASONING>\nThe helper moves to a new file greeting.py, app.py imports it from there, and app.py is then renamed to main.py.\n</REASONING>\n\n<WRITE path=\"greeting.py\">\ndef greet(name):\n    return f\"Hello, {name}!\"\n</WRITE>\n\n<EDIT path=\"app.py\">\n<<<<<<< SEARCH\ndef greet(name):\n    return f\"Hello, {name}!\"\n\n\nprint(greet(\"world\"))\n=======\nfrom greeting import greet\n\nprint(greet(\"world\"))\n>>>>>>> REPLACE\n</EDIT>\n\n<RENAME from=\"app.py\" to=\"main.py\" />";
+
+/// One change the model asked for.
+pub enum FileOperation {
+    Write { path: PathBuf, content: String },
+    Edit { path: PathBuf, blocks: Vec<Block> },
+    Delete { path: PathBuf },
+    Rename { from: PathBuf, to: PathBuf },
+}
+
+impl FileOperation {
+    pub fn describe(&self) -> String {
+        match self {
+            FileOperation::Write { path, .. } => format!("Write {}", path.display()),
+            FileOperation::Edit { path, blocks } => format!("Edit {} ({} blocks)", path.display(), blocks.len()),
+            FileOperation::Delete { path } => format!("Delete {}", path.display()),
+            FileOperation::Rename { from, to } => format!("Rename {} -> {}", from.display(), to.display()),
+        }
+    }
+}
+
+/// Builds the conversation for one attempt over the whole set of files,
+/// followed by `feedback` on the previous attempt if there was one.
+pub fn messages(instruction: &str, files: &[(PathBuf, String)], feedback: Option<&str>) -> Vec<ChatMessage> {
+    let example_files: Vec<(PathBuf, String)> = EXAMPLE_FILES
+        .iter()
+        .map(|(path, content)| (PathBuf::from(path), content.to_string()))
+        .collect();
+
+    vec![
+        ChatMessage::system(SYSTEM_PROMPT),
+        ChatMessage::user(user_prompt(EXAMPLE_INSTRUCTION, &example_files, None)),
+        ChatMessage::assistant(EXAMPLE_ANSWER),
+        ChatMessage::user(user_prompt(instruction, files, feedback)),
+    ]
+}
+
+fn user_prompt(instruction: &str, files: &[(PathBuf, String)], feedback: Option<&str>) -> String {
+    let mut prompt = format!("<INSTRUCTION>\n{}\n</INSTRUCTION>\n", instruction);
+    for (path, content) in files {
+        prompt.push_str(&format!("\n<FILE path=\"{}\">\n{}\n</FILE>\n", path.display(), content));
+    }
+    if let Some(feedback) = feedback {
+        prompt.push('\n');
+        prompt.push_str(feedback);
+    }
+    prompt
+}
+
+/// Parses the operations in the model's answer, in order. None at all means
+/// no file needs a change.
+pub fn parse(output: &str) -> Result<Vec<FileOperation>, Box<dyn Error>> {
+    let mut operations = Vec::new();
+    let mut rest = output;
+
+    while let Some(open) = rest.find('<') {
+        rest = &rest[open + 1..];
+        let Some(name) = ["WRITE", "EDIT", "DELETE", "RENAME"]
+            .into_iter()
+            .find(|name| rest.starts_with(name) && rest[name.len()..].starts_with(char::is_whitespace))
+        else {
+            continue;
+        };
+
+        let close = rest.find('>').ok_or_else(|| format!("Unterminated <{}> tag", name))?;
+        let tag = &rest[name.len()..close];
+        let self_closing = tag.trim_end().ends_with('/');
+        let attributes = parse_attributes(tag.trim_end().trim_end_matches('/'))?;
+        rest = &rest[close + 1..];
+
+        let attribute = |key: &str| -> Result<PathBuf, Box<dyn Error>> {
+            let value = attributes
+                .iter()
+                .find(|(name, _)| name == key)
+                .map(|(_, value)| value.as_str())
+                .ok_or_else(|| format!("<{}> is missing the `{}` attribute", name, key))?;
+            checked_path(value)
+        };
+
+        let body = if self_closing {
+            ""
+        } else {
+            let end_tag = format!("</{}>", name);
+            let end = rest.find(&end_tag).ok_or_else(|| format!("Missing {}", end_tag))?;
+            let body = &rest[..end];
+            rest = &rest[end + end_tag.len()..];
+            body
+        };
+
+        operations.push(match name {
+            "WRITE" => {
+                let content = body.trim_matches('\n');
+                FileOperation::Write {
+                    path: attribute("path")?,
+                    content: if content.is_empty() { String::new() } else { format!("{}\n", content) },
+                }
+            }
+            "EDIT" => FileOperation::Edit {
+                path: attribute("path")?,
+                blocks: format::parse_blocks(body)?,
+            },
+            "DELETE" => FileOperation::Delete { path: attribute("path")? },
+            _ => FileOperation::Rename {
+                from: attribute("from")?,
+                to: attribute("to")?,
+            },
+        });
+    }
+    Ok(operations)
+}
+
+fn parse_attributes(tag: &str) -> Result<Vec<(String, String)>, Box<dyn Error>> {
+    let mut attributes = Vec::new();
+    let mut rest = tag.trim();
+    while !rest.is_empty() {
+        let (key, value) = rest
+            .split_once("=\"")
+            .ok_or_else(|| format!("Malformed attributes: {}", tag))?;
+        let end = value.find('"').ok_or_else(|| format!("Malformed attributes: {}", tag))?;
+        attributes.push((key.trim().to_string(), value[..end].to_string()));
+        rest = value[end + 1..].trim_start();
+    }
+    Ok(attributes)
+}
+
+// Operations may only touch paths inside the project
+fn checked_path(path: &str) -> Result<PathBuf, Box<dyn Error>> {
+    let path = PathBuf::from(path);
+    if path.as_os_str().is_empty() || path.components().any(|c| !matches!(c, Component::Normal(_) | Component::CurDir)) {
+        return Err(format!("Refusing to touch path outside the project: {}", path.display()).into());
+    }
+    Ok(path)
+}
+
+// Content of a path before and after the operations; `None` means the file does not exist
+type Slot = (Option<String>, Option<String>);
+
+/// The effect of a list of operations: the before and after state of every
+/// path they touch. `None` means the file does not exist.
+pub struct ChangeSet {
+    changes: BTreeMap<PathBuf, Slot>,
+}
+
+impl ChangeSet {
+    /// Replays `operations` in memory on top of `files` without touching disk.
+    /// Other paths may only be used to create new files.
+    pub fn build(files: &[(PathBuf, String)], operations: &[FileOperation]) -> Result<Self, Box<dyn Error>> {
+        let mut state: BTreeMap<PathBuf, Slot> = files
+            .iter()
+            .map(|(path, content)| (normalize(path), (Some(content.clone()), Some(content.clone()))))
+            .collect();
+
+        // Outside the given files, only paths that do not exist yet may be touched, to create them
+        fn entry<'a>(state: &'a mut BTreeMap<PathBuf, Slot>, path: &Path) -> Result<&'a mut Slot, Box<dyn Error>> {
+            match state.entry(normalize(path)) {
+                Entry::Occupied(slot) => Ok(slot.into_mut()),
+                Entry::Vacant(_) if fs::symlink_metadata(path).is_ok() => {
+                    Err(format!("{} was not among the files sent to the model", path.display()).into())
+                }
+                Entry::Vacant(slot) => Ok(slot.insert((None, None))),
+            }
+        }
+
+        for operation in operations {
+            match operation {
+                FileOperation::Write { path, content } => entry(&mut state, path)?.1 = Some(content.clone()),
+                FileOperation::Edit { path, blocks } => {
+                    let slot = entry(&mut state, path)?;
+                    let current = slot.1.as_deref().ok_or_else(|| format!("Cannot edit missing file {}", path.display()))?;
+                    match format::apply_blocks(blocks, current) {
+                        Applied::Complete(content) => slot.1 = Some(content),
+                        Applied::Partial { .. } => {
+                            return Err(format!("Some SEARCH/REPLACE blocks for {} did not match", path.display()).into())
+                        }
+                    }
+                }
+                FileOperation::Delete { path } => {
+                    let slot = entry(&mut state, path)?;
+                    if slot.1.take().is_none() {
+                        return Err(format!("Cannot delete missing file {}", path.display()).into());
+                    }
+                }
+                FileOperation::Rename { from, to } => {
+                    let content = entry(&mut state, from)?
+                        .1
+                        .take()
+                        .ok_or_else(|| format!("Cannot rename missing file {}", from.display()))?;
+                    let target = entry(&mut state, to)?;
+                    if target.1.is_some() {
+                        return Err(format!("Cannot rename {} over existing file {}", from.display(), to.display()).into());
+                    }
+                    target.1 = Some(content);
+                }
+            }
+        }
+
+        state.retain(|_, (before, after)| before != after);
+        Ok(ChangeSet { changes: state })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.changes.is_empty()
+    }
+
+    /// Every touched path with its content before and after; `None` means the
+    /// file does not exist.
+    pub fn changes(&self) -> impl Iterator<Item = (&Path, Option<&str>, Option<&str>)> {
+        self.changes
+            .iter()
+            .map(|(path, (before, after))| (path.as_path(), before.as_deref(), after.as_deref()))
+    }
+
+    /// Replaces the new state of `path`, forgetting the path if that undoes
+    /// its change.
+    pub fn revise(&mut self, path: &Path, after: Option<String>) {
+        if let Some(slot) = self.changes.get_mut(path) {
+            slot.1 = after;
+            if slot.0 == slot.1 {
+                self.changes.remove(path);
+            }
+        }
+    }
+
+    /// Unified diff of every touched path; created and deleted files are
+    /// diffed against an empty file.
+    pub fn diff(&self) -> String {
+        self.changes
+            .iter()
+            .map(|(path, (before, after))| {
+                validation::unified_diff(
+                    &path.display().to_string(),
+                    before.as_deref().unwrap_or(""),
+                    after.as_deref().unwrap_or(""),
+                )
+            })
+            .collect()
+    }
+}
+
+/// Writes `content` to `path`, creating its directory, or deletes the file
+/// if `content` is `None`.
+pub fn write_state(path: &Path, content: Option<&str>) -> Result<(), Box<dyn Error>> {
+    match content {
+        Some(content) => {
+            if let Some(parent) = path.parent().filter(|parent| !parent.as_os_str().is_empty()) {
+                fs::create_dir_all(parent)?;
+            }
+            fs::write(path, content)?;
+        }
+        None if path.exists() => fs::remove_file(path)?,
+        None => {}
+    }
+    Ok(())
+}
+
+/// Writes `content` to `path`, creating its directory, through a temporary
+/// file so that an interrupted run never leaves half of it behind.
+pub fn write_atomically(path: &Path, content: &str) -> Result<(), Box<dyn Error>> {
+    let mut temporary = path.as_os_str().to_owned();
+    temporary.push(".tmp");
+    write_state(Path::new(&temporary), Some(content))?;
+    fs::rename(&temporary, path)?;
+    Ok(())
+}
+
+// `./a.py` and `a.py` are the same file
+fn normalize(path: &Path) -> PathBuf {
+    path.components().filter(|c| !matches!(c, Component::CurDir)).collect()
+}
@@ -0,0 +1,123 @@
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+
+use crate::journal::{self, STATE_DIR};
+use crate::multi_file;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum FileStatus {
+    /// Not processed yet, or the run stopped while processing it.
+    Pending,
+    /// The change was written.
+    Done,
+    /// Every attempt failed.
+    Failed,
+    /// Processed, but nothing needed to be written.
+    Skipped,
+}
+
+#[derive(Serialize, Deserialize)]
+struct State {
+    instruction_sha256: String,
+    model: String,
+    files: BTreeMap<String, FileStatus>,
+}
+
+/// The status of every file in runs of one instruction with one model, kept
+/// in `.refactoring-assistant/state/` so an interrupted run can be resumed.
+pub struct Progress {
+    path: PathBuf,
+    state: RefCell<State>,
+}
+
+impl Progress {
+    /// The progress of running `instruction` with `model`. With `resume`, it
+    /// starts from what earlier runs recorded.
+    pub fn load(instruction: &str, model: &str, resume: bool) -> Result<Self, Box<dyn Error>> {
+        let instruction_sha256 = journal::sha256(instruction);
+        let key = journal::sha256(&format!("{}\n{}", instruction_sha256, model));
+        let path = Path::new(STATE_DIR).join("state").join(format!("{}.json", &key[..16]));
+
+        let mut state = State {
+            instruction_sha256,
+            model: model.to_string(),
+            files: BTreeMap::new(),
+        };
+        if resume {
+            match fs::read_to_string(&path) {
+                Ok(saved) => state.files = serde_json::from_str::<State>(&saved)?.files,
+                Err(_) => println!("No earlier run of this instruction with model {} to resume; processing every file", model),
+            }
+        }
+        Ok(Progress {
+            path,
+            state: RefCell::new(state),
+        })
+    }
+
+    /// Drops the files an earlier run finished from `paths` and records the
+    /// others as pending.
+    pub fn start(&self, paths: Vec<PathBuf>) -> Result<Vec<PathBuf>, Box<dyn Error>> {
+        let mut state = self.state.borrow_mut();
+        let (finished, unfinished): (Vec<PathBuf>, Vec<PathBuf>) = paths.into_iter().partition(|path| {
+            matches!(
+                state.files.get(&path.display().to_string()),
+                Some(FileStatus::Done | FileStatus::Skipped)
+            )
+        });
+        if !finished.is_empty() {
+            println!(
+                "Resuming: {} files were already processed, {} left",
+                finished.len(),
+                unfinished.len()
+            );
+        }
+        for path in &unfinished {
+            state.files.insert(path.display().to_string(), FileStatus::Pending);
+        }
+        self.save(&state)?;
+        Ok(unfinished)
+    }
+
+    pub fn mark(&self, path: &Path, status: FileStatus) -> Result<(), Box<dyn Error>> {
+        let mut state = self.state.borrow_mut();
+        state.files.insert(path.display().to_string(), status);
+        self.save(&state)
+    }
+
+    /// Marks every file of `paths` that is still pending as `status`.
+    pub fn settle(&self, paths: &[PathBuf], status: FileStatus) -> Result<(), Box<dyn Error>> {
+        let mut state = self.state.borrow_mut();
+        for path in paths {
+            if let Some(current) = state.files.get_mut(&path.display().to_string()) {
+                if *current == FileStatus::Pending {
+                    *current = status;
+                }
+            }
+        }
+        self.save(&state)
+    }
+
+    /// Points out files that `--resume` would still process.
+    pub fn finish(&self) {
+        let state = self.state.borrow();
+        let unfinished = state
+            .files
+            .values()
+            .filter(|status| matches!(status, FileStatus::Pending | FileStatus::Failed))
+            .count();
+        if unfinished > 0 {
+            println!("{} files failed or were not processed; rerun with --resume to process only those", unfinished);
+        }
+    }
+
+    fn save(&self, state: &State) -> Result<(), Box<dyn Error>> {
+        multi_file::write_atomically(&self.path, &serde_json::to_string_pretty(state)?)
+    }
+}
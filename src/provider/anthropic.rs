@@ -0,0 +1,135 @@
+use std::env;
+use std::error::Error;
+
+use async_trait::async_trait;
+use reqwest::Client;
+use serde_json::json;
+
+use super::{ChatRequest, ChatResponse, FinishReason, Provider, ProviderConfig, Role, Usage};
+
+const DEFAULT_BASE_URL: &str = "https://api.anthropic.com/v1";
+const ANTHROPIC_VERSION: &str = "2023-06-01";
+// The Messages API requires an explicit output budget; this is large enough
+// for whole-file rewrites of typical source files.
+const MAX_TOKENS: u32 = 8192;
+
+/// Talks to the Anthropic Messages API (`/v1/messages`).
+pub struct AnthropicProvider {
+    client: Client,
+    base_url: String,
+    api_key: Option<String>,
+    headers: Vec<(String, String)>,
+}
+
+impl AnthropicProvider {
+    /// Reads the API key from `ANTHROPIC_API_KEY` unless `no_auth` is set.
+    pub fn from_config(config: ProviderConfig) -> Result<Self, Box<dyn Error>> {
+        let api_key = if config.no_auth {
+            None
+        } else {
+            Some(env::var("ANTHROPIC_API_KEY").map_err(|_| "ANTHROPIC_API_KEY must be set in the environment (or pass --no-auth)")?)
+        };
+
+        Ok(AnthropicProvider {
+            client: Client::new(),
+            base_url: config.base_url.unwrap_or_else(|| DEFAULT_BASE_URL.to_string()),
+            api_key,
+            headers: config.headers,
+        })
+    }
+}
+
+#[async_trait(?Send)]
+impl Provider for AnthropicProvider {
+    fn name(&self) -> &'static str {
+        "anthropic"
+    }
+
+    fn default_model(&self) -> &'static str {
+        "claude-sonnet-4-5"
+    }
+
+    async fn chat(&self, request: &ChatRequest) -> Result<ChatResponse, Box<dyn Error>> {
+        // The system prompt is a top-level field rather than a message
+        let system = request
+            .messages
+            .iter()
+            .filter(|message| message.role == Role::System)
+            .map(|message| message.content.as_str())
+            .collect::<Vec<_>>()
+            .join("\n\n");
+        let messages: Vec<_> = request
+            .messages
+            .iter()
+            .filter(|message| message.role != Role::System)
+            .collect();
+
+        let mut request_body = json!({
+            "model": request.model,
+            "max_tokens": MAX_TOKENS,
+            "messages": messages
+        });
+        if !system.is_empty() {
+            request_body["system"] = json!(system);
+        }
+        // Structured output is done by forcing a call to a tool whose input is the schema
+        if let Some(schema) = &request.response_schema {
+            request_body["tools"] = json!([{
+                "name": schema.name,
+                "description": "Report the result of the refactoring.",
+                "input_schema": schema.schema
+            }]);
+            request_body["tool_choice"] = json!({ "type": "tool", "name": schema.name });
+        }
+
+        let url = format!("{}/messages", self.base_url.trim_end_matches('/'));
+        let mut builder = self
+            .client
+            .post(&url)
+            .header("anthropic-version", ANTHROPIC_VERSION)
+            .json(&request_body);
+        if let Some(api_key) = &self.api_key {
+            builder = builder.header("x-api-key", api_key);
+        }
+        for (name, value) in &self.headers {
+            builder = builder.header(name.as_str(), value.as_str());
+        }
+
+        let response = builder.send().await?;
+        let status = response.status();
+        let body = response.text().await?;
+        if !status.is_success() {
+            return Err(format!("{} returned {}: {}", url, status, body).into());
+        }
+
+        let response_json: serde_json::Value = serde_json::from_str(&body)?;
+        let blocks = response_json["content"]
+            .as_array()
+            .ok_or("Failed to parse the response")?;
+        let tool_input = blocks.iter().find(|block| block["type"] == "tool_use").map(|block| block["input"].to_string());
+        let content: String = match tool_input {
+            Some(input) => input,
+            None => blocks
+                .iter()
+                .filter(|block| block["type"] == "text")
+                .filter_map(|block| block["text"].as_str())
+                .collect(),
+        };
+
+        let finish_reason = match response_json["stop_reason"].as_str() {
+            Some("end_turn") | Some("stop_sequence") | Some("tool_use") | None => FinishReason::Stop,
+            Some("max_tokens") => FinishReason::Length,
+            Some("refusal") => return Err("The model refused to answer".into()),
+            Some(other) => FinishReason::Other(other.to_string()),
+        };
+
+        Ok(ChatResponse {
+            content,
+            usage: Usage {
+                input_tokens: response_json["usage"]["input_tokens"].as_u64(),
+                output_tokens: response_json["usage"]["output_tokens"].as_u64(),
+            },
+            finish_reason,
+        })
+    }
+}
@@ -0,0 +1,136 @@
+use std::error::Error;
+
+use async_trait::async_trait;
+use serde::Serialize;
+
+mod anthropic;
+mod ollama;
+mod openai;
+
+pub use anthropic::AnthropicProvider;
+pub use ollama::OllamaProvider;
+pub use openai::OpenAiProvider;
+
+/// Names accepted by `--provider`.
+pub const PROVIDERS: &[&str] = &["openai", "anthropic", "ollama"];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Role {
+    System,
+    User,
+    Assistant,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ChatMessage {
+    pub role: Role,
+    pub content: String,
+}
+
+impl ChatMessage {
+    pub fn system(content: impl Into<String>) -> Self {
+        ChatMessage { role: Role::System, content: content.into() }
+    }
+
+    pub fn user(content: impl Into<String>) -> Self {
+        ChatMessage { role: Role::User, content: content.into() }
+    }
+
+    pub fn assistant(content: impl Into<String>) -> Self {
+        ChatMessage { role: Role::Assistant, content: content.into() }
+    }
+}
+
+/// A JSON schema the answer must conform to.
+#[derive(Debug, Clone)]
+pub struct ResponseSchema {
+    pub name: String,
+    pub schema: serde_json::Value,
+}
+
+#[derive(Debug, Clone)]
+pub struct ChatRequest {
+    pub model: String,
+    pub messages: Vec<ChatMessage>,
+    /// When set, the backend is asked for structured output matching the
+    /// schema and the answer's JSON text is returned as `content`.
+    pub response_schema: Option<ResponseSchema>,
+}
+
+/// Token accounting as reported by the backend. Fields are `None` when the
+/// backend does not report them.
+#[derive(Debug, Clone, Default)]
+pub struct Usage {
+    pub input_tokens: Option<u64>,
+    pub output_tokens: Option<u64>,
+}
+
+/// Why the backend stopped generating.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FinishReason {
+    /// The model finished its answer.
+    Stop,
+    /// The output token limit was hit, so the answer is truncated.
+    Length,
+    /// Anything else the backend reported, kept verbatim.
+    Other(String),
+}
+
+#[derive(Debug, Clone)]
+pub struct ChatResponse {
+    pub content: String,
+    pub usage: Usage,
+    pub finish_reason: FinishReason,
+}
+
+/// Connection settings shared by all backends, taken from the command line.
+#[derive(Debug, Clone, Default)]
+pub struct ProviderConfig {
+    /// Overrides the backend's default API root (e.g. `http://localhost:8000/v1`).
+    pub base_url: Option<String>,
+    /// Extra headers sent with every request.
+    pub headers: Vec<(String, String)>,
+    /// Skip reading an API key and send no `Authorization` header.
+    pub no_auth: bool,
+    /// Download the requested model if the backend does not have it yet.
+    pub pull_missing_model: bool,
+}
+
+/// Parses a `Name: Value` header given on the command line.
+pub fn parse_header(header: &str) -> Result<(String, String), Box<dyn Error>> {
+    let (name, value) = header
+        .split_once(':')
+        .ok_or_else(|| format!("Invalid header `{}`, expected `Name: Value`", header))?;
+    let name = name.trim();
+    if name.is_empty() {
+        return Err(format!("Invalid header `{}`, header name is empty", header).into());
+    }
+    Ok((name.to_string(), value.trim().to_string()))
+}
+
+/// A chat-completion backend the refactoring loop can talk to.
+#[async_trait(?Send)]
+pub trait Provider {
+    fn name(&self) -> &'static str;
+
+    /// Model used when `--model` is not given.
+    fn default_model(&self) -> &'static str;
+
+    /// Verifies the backend is reachable and serves `model`. Called once
+    /// before any file is processed so misconfiguration fails fast.
+    async fn check(&self, _model: &str) -> Result<(), Box<dyn Error>> {
+        Ok(())
+    }
+
+    async fn chat(&self, request: &ChatRequest) -> Result<ChatResponse, Box<dyn Error>>;
+}
+
+pub fn build_provider(name: &str, config: ProviderConfig) -> Result<Box<dyn Provider>, Box<dyn Error>> {
+    match name {
+        "openai" => Ok(Box::new(OpenAiProvider::from_config(config)?)),
+        "anthropic" => Ok(Box::new(AnthropicProvider::from_config(config)?)),
+        "ollama" => Ok(Box::new(OllamaProvider::from_config(config)?)),
+        other => Err(format!("Unknown provider: {}", other).into()),
+    }
+}
@@ -0,0 +1,263 @@
+use std::env;
+use std::error::Error;
+
+use async_trait::async_trait;
+use reqwest::Client;
+use serde_json::json;
+
+use super::{ChatRequest, ChatResponse, FinishReason, Provider, ProviderConfig, Usage};
+
+const DEFAULT_BASE_URL: &str = "http://localhost:11434";
+
+/// Talks to a local Ollama daemon (`/api/chat`).
+pub struct OllamaProvider {
+    client: Client,
+    base_url: String,
+    headers: Vec<(String, String)>,
+    pull_missing_model: bool,
+}
+
+impl OllamaProvider {
+    /// Uses `--base-url`, then `OLLAMA_HOST`, then the daemon's default address.
+    pub fn from_config(config: ProviderConfig) -> Result<Self, Box<dyn Error>> {
+        let base_url = config
+            .base_url
+            .or_else(|| env::var("OLLAMA_HOST").ok())
+            .unwrap_or_else(|| DEFAULT_BASE_URL.to_string());
+        let base_url = if base_url.contains("://") { base_url } else { format!("http://{}", base_url) };
+
+        Ok(OllamaProvider {
+            client: Client::new(),
+            base_url: base_url.trim_end_matches('/').to_string(),
+            headers: config.headers,
+            pull_missing_model: config.pull_missing_model,
+        })
+    }
+
+    async fn get_json(&self, path: &str) -> Result<serde_json::Value, Box<dyn Error>> {
+        let url = format!("{}{}", self.base_url, path);
+        let mut builder = self.client.get(&url);
+        for (name, value) in &self.headers {
+            builder = builder.header(name.as_str(), value.as_str());
+        }
+        let response = builder
+            .send()
+            .await
+            .map_err(|e| format!("Ollama is not reachable at {}: {}", self.base_url, e))?;
+        self.read_json(&url, response).await
+    }
+
+    async fn post_json(&self, path: &str, body: &serde_json::Value) -> Result<serde_json::Value, Box<dyn Error>> {
+        let url = format!("{}{}", self.base_url, path);
+        let mut builder = self.client.post(&url).json(body);
+        for (name, value) in &self.headers {
+            builder = builder.header(name.as_str(), value.as_str());
+        }
+        let response = builder.send().await?;
+        self.read_json(&url, response).await
+    }
+
+    async fn read_json(&self, url: &str, response: reqwest::Response) -> Result<serde_json::Value, Box<dyn Error>> {
+        let status = response.status();
+        let body = response.text().await?;
+        if !status.is_success() {
+            return Err(format!("{} returned {}: {}", url, status, body).into());
+        }
+        Ok(serde_json::from_str(&body)?)
+    }
+}
+
+// Ollama reports untagged models as `name:latest`
+fn same_model(installed: &str, requested: &str) -> bool {
+    installed == requested || (!requested.contains(':') && installed == format!("{}:latest", requested))
+}
+
+#[async_trait(?Send)]
+impl Provider for OllamaProvider {
+    fn name(&self) -> &'static str {
+        "ollama"
+    }
+
+    fn default_model(&self) -> &'static str {
+        "llama3.1"
+    }
+
+    async fn check(&self, model: &str) -> Result<(), Box<dyn Error>> {
+        let tags = self.get_json("/api/tags").await?;
+        let installed = tags["models"]
+            .as_array()
+            .ok_or("Failed to parse the Ollama model list")?
+            .iter()
+            .filter_map(|entry| entry["name"].as_str())
+            .any(|name| same_model(name, model));
+        if installed {
+            return Ok(());
+        }
+
+        if !self.pull_missing_model {
+            return Err(format!("Model {} is not available in Ollama; run `ollama pull {}` or pass --pull", model, model).into());
+        }
+
+        println!("Pulling model {} into Ollama...", model);
+        let status = self.post_json("/api/pull", &json!({ "model": model, "stream": false })).await?;
+        if let Some(error) = status["error"].as_str() {
+            return Err(format!("Failed to pull model {}: {}", model, error).into());
+        }
+        Ok(())
+    }
+
+    async fn chat(&self, request: &ChatRequest) -> Result<ChatResponse, Box<dyn Error>> {
+        let mut request_body = json!({
+            "model": request.model,
+            "messages": request.messages,
+            "stream": false
+        });
+        if let Some(schema) = &request.response_schema {
+            request_body["format"] = schema.schema.clone();
+        }
+
+        let response_json = self.post_json("/api/chat", &request_body).await?;
+        let content = response_json["message"]["content"]
+            .as_str()
+            .ok_or("Failed to parse the response")?;
+
+        Ok(ChatResponse {
+            content: content.to_string(),
+            usage: Usage {
+                input_tokens: response_json["prompt_eval_count"].as_u64(),
+                output_tokens: response_json["eval_count"].as_u64(),
+            },
+            finish_reason: match response_json["done_reason"].as_str() {
+                Some("stop") | None => FinishReason::Stop,
+                Some("length") => FinishReason::Length,
+                Some(other) => FinishReason::Other(other.to_string()),
+            },
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    use super::*;
+
+    // A stand-in daemon answering each path with a fixed status and body. The paths
+    // it was asked for, in order.
+    async fn serve(routes: Vec<(&'static str, u16, serde_json::Value)>) -> (String, Arc<Mutex<Vec<String>>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let base_url = format!("http://{}", listener.local_addr().unwrap());
+        let requested = Arc::new(Mutex::new(Vec::new()));
+        let log = Arc::clone(&requested);
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                let mut request = Vec::new();
+                let mut buffer = [0; 4096];
+                // Headers, then as much body as they announce
+                let (head, body_length) = loop {
+                    let read = stream.read(&mut buffer).await.unwrap();
+                    request.extend_from_slice(&buffer[..read]);
+                    let text = String::from_utf8_lossy(&request).to_string();
+                    if let Some((head, _)) = text.split_once("\r\n\r\n") {
+                        let length = head
+                            .lines()
+                            .find_map(|line| line.to_ascii_lowercase().strip_prefix("content-length:").map(|value| value.trim().parse().unwrap()))
+                            .unwrap_or(0);
+                        break (head.to_string(), length);
+                    }
+                };
+                while request.len() < head.len() + 4 + body_length {
+                    let read = stream.read(&mut buffer).await.unwrap();
+                    request.extend_from_slice(&buffer[..read]);
+                }
+
+                let path = head.split_whitespace().nth(1).unwrap().to_string();
+                log.lock().unwrap().push(path.clone());
+                let (status, body) = routes
+                    .iter()
+                    .find(|(route, _, _)| *route == path)
+                    .map_or((404, serde_json::Value::Null), |(_, status, body)| (*status, body.clone()));
+                let body = body.to_string();
+                let response = format!(
+                    "HTTP/1.1 {} Stand-in\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                    status,
+                    body.len(),
+                    body
+                );
+                stream.write_all(response.as_bytes()).await.unwrap();
+            }
+        });
+        (base_url, requested)
+    }
+
+    fn provider(base_url: String, pull_missing_model: bool) -> OllamaProvider {
+        OllamaProvider::from_config(ProviderConfig {
+            base_url: Some(base_url),
+            headers: Vec::new(),
+            no_auth: true,
+            pull_missing_model,
+        })
+        .unwrap()
+    }
+
+    fn tags() -> (&'static str, u16, serde_json::Value) {
+        ("/api/tags", 200, json!({ "models": [{ "name": "llama3.1:latest" }, { "name": "qwen2.5-coder:7b" }] }))
+    }
+
+    #[tokio::test]
+    async fn unreachable_daemon_is_reported() {
+        // Nothing listens on a port that was just released
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let base_url = format!("http://{}", listener.local_addr().unwrap());
+        drop(listener);
+
+        let error = provider(base_url, false).check("llama3.1").await.unwrap_err();
+        assert!(error.to_string().contains("Ollama is not reachable"), "{}", error);
+    }
+
+    #[tokio::test]
+    async fn installed_model_passes() {
+        let (base_url, requested) = serve(vec![tags()]).await;
+        let ollama = provider(base_url, false);
+        ollama.check("llama3.1").await.unwrap();
+        ollama.check("llama3.1:latest").await.unwrap();
+        ollama.check("qwen2.5-coder:7b").await.unwrap();
+        assert!(requested.lock().unwrap().iter().all(|path| path == "/api/tags"));
+    }
+
+    #[test]
+    fn untagged_names_only_match_latest() {
+        assert!(same_model("llama3.1:latest", "llama3.1"));
+        assert!(!same_model("qwen2.5-coder:7b", "qwen2.5-coder"));
+        assert!(!same_model("llama3.1:latest", "llama3.1:8b"));
+    }
+
+    #[tokio::test]
+    async fn missing_model_is_not_pulled_without_pull() {
+        let (base_url, requested) = serve(vec![tags()]).await;
+        let error = provider(base_url, false).check("qwen2.5-coder").await.unwrap_err();
+        assert!(error.to_string().contains("ollama pull qwen2.5-coder"), "{}", error);
+        assert_eq!(*requested.lock().unwrap(), ["/api/tags"]);
+    }
+
+    #[tokio::test]
+    async fn missing_model_is_pulled_with_pull() {
+        let (base_url, requested) = serve(vec![tags(), ("/api/pull", 200, json!({ "status": "success" }))]).await;
+        provider(base_url, true).check("mistral").await.unwrap();
+        assert_eq!(*requested.lock().unwrap(), ["/api/tags", "/api/pull"]);
+    }
+
+    #[tokio::test]
+    async fn pull_errors_are_reported() {
+        let (base_url, _) = serve(vec![tags(), ("/api/pull", 200, json!({ "error": "pull model manifest: file does not exist" }))]).await;
+        let error = provider(base_url, true).check("no-such-model").await.unwrap_err();
+        assert!(error.to_string().contains("Failed to pull model no-such-model: pull model manifest"), "{}", error);
+
+        let (base_url, _) = serve(vec![tags(), ("/api/pull", 500, json!({ "error": "disk full" }))]).await;
+        let error = provider(base_url, true).check("mistral").await.unwrap_err();
+        assert!(error.to_string().contains("500"), "{}", error);
+    }
+}
@@ -0,0 +1,99 @@
+use std::env;
+use std::error::Error;
+
+use async_trait::async_trait;
+use reqwest::Client;
+use serde_json::json;
+
+use super::{ChatRequest, ChatResponse, FinishReason, Provider, ProviderConfig, Usage};
+
+const DEFAULT_BASE_URL: &str = "https://api.openai.com/v1";
+
+/// Talks to OpenAI or any server implementing the same `/chat/completions`
+/// protocol (vLLM, LocalAI, LM Studio, internal gateways).
+pub struct OpenAiProvider {
+    client: Client,
+    base_url: String,
+    api_key: Option<String>,
+    headers: Vec<(String, String)>,
+}
+
+impl OpenAiProvider {
+    /// Reads the API key from `OPENAI_API_KEY` unless `no_auth` is set.
+    pub fn from_config(config: ProviderConfig) -> Result<Self, Box<dyn Error>> {
+        let api_key = if config.no_auth {
+            None
+        } else {
+            Some(env::var("OPENAI_API_KEY").map_err(|_| "OPENAI_API_KEY must be set in the environment (or pass --no-auth)")?)
+        };
+
+        Ok(OpenAiProvider {
+            client: Client::new(),
+            base_url: config.base_url.unwrap_or_else(|| DEFAULT_BASE_URL.to_string()),
+            api_key,
+            headers: config.headers,
+        })
+    }
+}
+
+#[async_trait(?Send)]
+impl Provider for OpenAiProvider {
+    fn name(&self) -> &'static str {
+        "openai"
+    }
+
+    fn default_model(&self) -> &'static str {
+        "gpt-4"
+    }
+
+    async fn chat(&self, request: &ChatRequest) -> Result<ChatResponse, Box<dyn Error>> {
+        let mut request_body = json!({
+            "model": request.model,
+            "messages": request.messages
+        });
+        if let Some(schema) = &request.response_schema {
+            request_body["response_format"] = json!({
+                "type": "json_schema",
+                "json_schema": {
+                    "name": schema.name,
+                    "strict": true,
+                    "schema": schema.schema
+                }
+            });
+        }
+
+        let url = format!("{}/chat/completions", self.base_url.trim_end_matches('/'));
+        let mut builder = self.client.post(&url).json(&request_body);
+        if let Some(api_key) = &self.api_key {
+            builder = builder.header("Authorization", format!("Bearer {}", api_key));
+        }
+        for (name, value) in &self.headers {
+            builder = builder.header(name.as_str(), value.as_str());
+        }
+
+        let response = builder.send().await?;
+        let status = response.status();
+        let body = response.text().await?;
+        if !status.is_success() {
+            return Err(format!("{} returned {}: {}", url, status, body).into());
+        }
+
+        let response_json: serde_json::Value = serde_json::from_str(&body)?;
+        let content = response_json["choices"][0]["message"]["content"]
+            .as_str()
+            .ok_or("Failed to parse the response")?;
+
+        Ok(ChatResponse {
+            content: content.to_string(),
+            usage: Usage {
+                input_tokens: response_json["usage"]["prompt_tokens"].as_u64(),
+                output_tokens: response_json["usage"]["completion_tokens"].as_u64(),
+            },
+            finish_reason: match response_json["choices"][0]["finish_reason"].as_str() {
+                Some("stop") | None => FinishReason::Stop,
+                Some("length") => FinishReason::Length,
+                Some(other) => FinishReason::Other(other.to_string()),
+            },
+        })
+    }
+}
@@ -0,0 +1,1055 @@
+use std::cell::RefCell;
+use std::collections::{BTreeMap, HashMap};
+use std::error::Error;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::assertions::{self, Assertions};
+use crate::diagnostics::{DiagnosticsFormat, Severity};
+use crate::dry_run::DryRun;
+use crate::format::{Applied, OutputFormat};
+use crate::guard::{self, Truncated};
+use crate::interrupt::Interrupted;
+use crate::journal::Journal;
+use crate::multi_file::{self, ChangeSet};
+use crate::progress::{FileStatus, Progress};
+use crate::provider::{ChatMessage, ChatRequest, ChatResponse, FinishReason, Provider};
+use crate::review::{self, Verdict};
+use crate::validation::{self, Equivalence, ValidationLimits, ValidationOutcome};
+use crate::validators::Validators;
+use crate::workspace::Scratch;
+
+// How many times a partially applied answer is sent back for the failed edits
+const MAX_REPAIRS: usize = 2;
+
+/// Names accepted by `--retry-strategy`.
+pub const RETRY_STRATEGIES: &[&str] = &["fresh", "iterative", "best-of"];
+
+/// How attempts after a failed validation are produced.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RetryStrategy {
+    /// Every attempt starts again from the original file, with the previous
+    /// attempt's errors in the prompt.
+    Fresh,
+    /// Keep the conversation going: the model sees the errors and repairs its
+    /// own previous candidate.
+    Iterative,
+    /// Every attempt starts from the original file; all attempts are run and
+    /// the passing one with the fewest diagnostics is kept.
+    BestOf,
+}
+
+impl RetryStrategy {
+    pub fn from_name(name: &str) -> Result<Self, Box<dyn Error>> {
+        match name {
+            "fresh" => Ok(RetryStrategy::Fresh),
+            "iterative" => Ok(RetryStrategy::Iterative),
+            "best-of" => Ok(RetryStrategy::BestOf),
+            other => Err(format!("Unknown retry strategy: {}", other).into()),
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            RetryStrategy::Fresh => "fresh",
+            RetryStrategy::Iterative => "iterative",
+            RetryStrategy::BestOf => "best-of",
+        }
+    }
+}
+
+/// Names accepted by `--baseline`.
+pub const BASELINE_MODES: &[&str] = &["off", "abort", "compare"];
+
+/// What to do about a validation command that already fails before any edit.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BaselineMode {
+    /// Do not run the command on the untouched tree.
+    Off,
+    /// Stop before touching any file.
+    Abort,
+    /// Accept candidates that add no failures of their own.
+    Compare,
+}
+
+impl BaselineMode {
+    pub fn from_name(name: &str) -> Result<Self, Box<dyn Error>> {
+        match name {
+            "off" => Ok(BaselineMode::Off),
+            "abort" => Ok(BaselineMode::Abort),
+            "compare" => Ok(BaselineMode::Compare),
+            other => Err(format!("Unknown baseline mode: {}", other).into()),
+        }
+    }
+}
+
+/// Settings shared by every file in a run.
+pub struct RunConfig {
+    pub instruction: String,
+    pub model: String,
+    pub output_format: OutputFormat,
+    /// Ask for a full rewrite when edits in `output_format` cannot be applied.
+    pub full_fallback: bool,
+    /// Chooses the validation command for each file, if validating at all.
+    pub validators: Option<Validators>,
+    /// How the validation command's output is parsed.
+    pub diagnostics_format: DiagnosticsFormat,
+    pub validation_limits: ValidationLimits,
+    /// Failing validation runs on the untouched tree, by command; candidates
+    /// validated by one of these commands are only judged on the failures
+    /// they add.
+    pub baseline: HashMap<String, ValidationOutcome>,
+    /// Test results every candidate must reproduce, if checking behaviour.
+    pub equivalence: Option<Equivalence>,
+    /// Expectations on every candidate, checked before it is validated.
+    pub assertions: Assertions,
+    /// Accept candidates that lose most of the file's lines.
+    pub allow_shrink: bool,
+    /// How many times an answer cut off at the output token limit is
+    /// continued before giving up on it.
+    pub max_continuations: usize,
+    pub n_retries: usize,
+    pub retry_strategy: RetryStrategy,
+    /// Scratch copy in which candidates are validated; the live files are
+    /// only written once a candidate is accepted.
+    pub scratch: Option<Scratch>,
+    /// Set when accepted changes are only shown, never written.
+    pub dry_run: Option<DryRun>,
+    /// Let the user review every candidate hunk by hunk before it is written
+    /// or validated.
+    pub interactive: bool,
+    /// Records every file the run writes so it can be undone; none in a dry run.
+    pub journal: Option<Journal>,
+    /// Status of every file, kept so an interrupted run can be resumed; none
+    /// in a dry run.
+    pub progress: Option<Progress>,
+    /// Live files holding a candidate that was not accepted yet, with the
+    /// content to put back if the run stops; `None` means the file did not
+    /// exist. Only used without a scratch copy.
+    pub unaccepted: RefCell<BTreeMap<PathBuf, Option<String>>>,
+}
+
+impl RunConfig {
+    // Where a candidate for `path` is written before it is accepted
+    fn staged_path(&self, path: &Path) -> Result<PathBuf, Box<dyn Error>> {
+        match &self.scratch {
+            Some(scratch) => scratch.path_for(path),
+            None => Ok(path.to_path_buf()),
+        }
+    }
+
+    fn validation_dir(&self) -> Option<&Path> {
+        self.scratch.as_ref().map(Scratch::workdir)
+    }
+
+    // Writes `content` where candidates for `path` are validated, remembering `original` while that
+    // leaves a candidate in the live file. `None` means the file does not exist.
+    fn stage(&self, path: &Path, original: Option<&str>, content: Option<&str>) -> Result<(), Box<dyn Error>> {
+        multi_file::write_state(&self.staged_path(path)?, content)?;
+        if self.scratch.is_none() {
+            let mut unaccepted = self.unaccepted.borrow_mut();
+            if content == original {
+                unaccepted.remove(path);
+            } else {
+                unaccepted.entry(path.to_path_buf()).or_insert_with(|| original.map(String::from));
+            }
+        }
+        Ok(())
+    }
+
+    // Puts `original` back where candidates for `path` are validated if a rejected candidate is
+    // still there. Whether anything had to be written.
+    fn reset_staged(&self, path: &Path, original: &str) -> Result<bool, Box<dyn Error>> {
+        if fs::read_to_string(self.staged_path(path)?)? == original {
+            return Ok(false);
+        }
+        self.stage(path, Some(original), Some(original))?;
+        Ok(true)
+    }
+
+    /// Puts back the original content of every live file that holds a
+    /// candidate which was not accepted.
+    pub fn restore_unaccepted(&self) -> Result<(), Box<dyn Error>> {
+        let unaccepted = std::mem::take(&mut *self.unaccepted.borrow_mut());
+        for (path, original) in unaccepted {
+            multi_file::write_state(&path, original.as_deref())?;
+            println!("Restored original content for {}", path.display());
+        }
+        Ok(())
+    }
+
+    // Writes an accepted candidate to the live file, or only shows its diff in a dry run
+    fn accept(&self, path: &Path, original: &str, content: &str) -> Result<(), Box<dyn Error>> {
+        match &self.dry_run {
+            Some(dry_run) => dry_run.record(path, Some(original), Some(content)),
+            None => {
+                fs::write(path, content)?;
+                self.unaccepted.borrow_mut().remove(path);
+                if let Some(journal) = &self.journal {
+                    journal.record(path, Some(original), Some(content))?;
+                }
+                self.mark(path, FileStatus::Done)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn accept_change_set(&self, change_set: &ChangeSet) -> Result<(), Box<dyn Error>> {
+        match &self.dry_run {
+            Some(dry_run) => {
+                for (path, before, after) in change_set.changes() {
+                    dry_run.record(path, before, after);
+                }
+            }
+            None => {
+                for (path, before, after) in change_set.changes() {
+                    // Without a scratch copy a validated change is already in the live file
+                    if fs::read_to_string(path).ok().as_deref() != after {
+                        multi_file::write_state(path, after)?;
+                    }
+                    self.unaccepted.borrow_mut().remove(path);
+                    if let Some(journal) = &self.journal {
+                        journal.record(path, before, after)?;
+                    }
+                    self.mark(path, FileStatus::Done)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    pub fn mark(&self, path: &Path, status: FileStatus) -> Result<(), Box<dyn Error>> {
+        match &self.progress {
+            Some(progress) => progress.mark(path, status),
+            None => Ok(()),
+        }
+    }
+
+    pub fn settle(&self, paths: &[PathBuf], status: FileStatus) -> Result<(), Box<dyn Error>> {
+        match &self.progress {
+            Some(progress) => progress.settle(paths, status),
+            None => Ok(()),
+        }
+    }
+
+    // Start of the message reporting an accepted candidate
+    fn applied(&self) -> &'static str {
+        match self.dry_run {
+            Some(_) => "Changes would be applied",
+            None => "Changes applied",
+        }
+    }
+
+    fn checks_anything(&self, command: Option<&str>) -> bool {
+        command.is_some() || self.equivalence.is_some()
+    }
+}
+
+/// Runs every validation command that `paths` will use once on the
+/// untouched tree. In `Compare` mode the failing outcomes are returned, keyed
+/// by command, to judge candidates against.
+pub fn run_baseline(
+    config: &RunConfig,
+    mode: BaselineMode,
+    paths: &[PathBuf],
+    multi_file: bool,
+) -> Result<HashMap<String, ValidationOutcome>, Box<dyn Error>> {
+    let mut baseline = HashMap::new();
+    let Some(validators) = &config.validators else {
+        return Ok(baseline);
+    };
+    if mode == BaselineMode::Off {
+        return Ok(baseline);
+    }
+
+    let mut commands = Vec::new();
+    if multi_file {
+        commands.extend(validators.batch_command(paths)?);
+    } else {
+        for path in paths {
+            if let Some(command) = validators.command_for(path)? {
+                if !commands.contains(&command) {
+                    commands.push(command);
+                }
+            }
+        }
+    }
+
+    for command in commands {
+        println!("Running baseline validation `{}` before any change", command);
+        let outcome = validation::validate_change(&command, config.diagnostics_format, config.validation_dir(), &config.validation_limits)?;
+        if outcome.success {
+            println!("Baseline validation passed");
+            continue;
+        }
+
+        let errors = outcome.diagnostics.iter().filter(|d| d.severity == Severity::Error).count();
+        let description = match outcome.timed_out {
+            Some(timeout) => format!("timed out after {}s", timeout.as_secs()),
+            None => format!("failed with {} errors", errors),
+        };
+        match mode {
+            BaselineMode::Compare if outcome.timed_out.is_none() => {
+                println!("Baseline validation {}; candidates will only be judged on new failures", description);
+                baseline.insert(command, outcome);
+            }
+            _ => {
+                return Err(format!(
+                    "Validation `{}` already {} before any change; fix it first or use --baseline compare",
+                    command, description
+                )
+                .into())
+            }
+        }
+    }
+    Ok(baseline)
+}
+
+pub async fn process_file(path: &Path, provider: &dyn Provider, config: &RunConfig) -> Result<(), Box<dyn Error>> {
+    let RunConfig {
+        instruction,
+        n_retries,
+        retry_strategy: strategy,
+        ..
+    } = config;
+    let (n_retries, strategy) = (*n_retries, *strategy);
+    let label = path.display().to_string();
+    let original_content = fs::read_to_string(path)?;
+    let validate_command = match &config.validators {
+        Some(validators) => validators.command_for(path)?,
+        None => None,
+    };
+    let baseline = validate_command.as_ref().and_then(|command| config.baseline.get(command));
+    if let Some(command) = &validate_command {
+        println!("Validating {} with `{}`", path.display(), command);
+    }
+    // What the next attempt starts from; only iterative repair moves it away from the original
+    let mut base_content = original_content.clone();
+    // What went wrong with the previous attempt, shown to the model on the next one
+    let mut feedback: Option<String> = None;
+    // Kept across attempts by iterative repair, rebuilt every attempt otherwise
+    let mut conversation: Vec<ChatMessage> = Vec::new();
+    let mut conversation_format = config.output_format;
+    // Best passing candidate so far for best-of: (diagnostics, attempt, content)
+    let mut best: Option<(usize, usize, String)> = None;
+
+    // Retry mechanism
+    for attempt in 0..n_retries {
+        println!("Processing file {} (attempt {}/{})", path.display(), attempt + 1, n_retries);
+
+        if strategy != RetryStrategy::Iterative || conversation.is_empty() {
+            conversation_format = config.output_format;
+            conversation = conversation_format.messages(instruction, &base_content, feedback.as_deref());
+        }
+        let result = request_with_fallback(
+            &label,
+            provider,
+            config,
+            &mut conversation_format,
+            &mut conversation,
+            &base_content,
+            feedback.as_deref(),
+        )
+        .await?;
+        let mut transformed_content = match result {
+            Ok(content) => content,
+            Err(e) => {
+                eprintln!("Could not apply response for {}: {}", path.display(), e);
+                feedback = Some(if e.is::<Truncated>() {
+                    guard::truncated_prompt()
+                } else {
+                    guard::unusable_prompt(&*e)
+                });
+                // An unusable answer is not worth continuing from
+                conversation.clear();
+                continue;
+            }
+        };
+        if transformed_content == original_content {
+            // Iterative repair may have left a rejected attempt in the staged file
+            config.reset_staged(path, &original_content)?;
+            println!("No changes needed for {}", path.display());
+            return config.mark(path, FileStatus::Skipped);
+        }
+
+        // Never write an answer that leaves parts of the file out
+        let problems = guard::check(&base_content, &transformed_content, config.allow_shrink);
+        if !problems.is_empty() {
+            feedback = Some(reject_incomplete(&label, &problems));
+            conversation.clear();
+            continue;
+        }
+
+        let mut failures = config.assertions.check(&original_content, &transformed_content);
+        if failures.is_empty() && config.interactive {
+            match review::review(path, &base_content, &transformed_content)? {
+                Verdict::Keep(content) if content == original_content => {
+                    // Iterative repair may have left a rejected attempt in the staged file
+                    config.reset_staged(path, &original_content)?;
+                    println!("No changes kept for {}", path.display());
+                    return config.mark(path, FileStatus::Skipped);
+                }
+                Verdict::Keep(content) if content != transformed_content => {
+                    // Skipped and edited hunks are held to the same checks as the model's answer
+                    let problems = guard::check(&base_content, &content, config.allow_shrink);
+                    if !problems.is_empty() {
+                        feedback = Some(reject_incomplete(&format!("{} after review", label), &problems));
+                        conversation.clear();
+                        continue;
+                    }
+                    failures = config.assertions.check(&original_content, &content);
+                    transformed_content = content;
+                }
+                Verdict::Keep(_) => {}
+                Verdict::Redo(prompt) => {
+                    println!("Asking the model to redo the change for {}", path.display());
+                    feedback = Some(prompt);
+                    conversation.clear();
+                    continue;
+                }
+            }
+        }
+
+        let diff = validation::unified_diff(&label, &base_content, &transformed_content);
+        let attempt_feedback = if !failures.is_empty() {
+            // Checked before anything is written or validated
+            println!("Assertions failed for {}, retrying...", path.display());
+            for failure in &failures {
+                println!("  {}", failure);
+            }
+            assertions::feedback_prompt(&diff, &failures)
+        } else if !config.checks_anything(validate_command.as_deref()) {
+            // If there is nothing to check, consider the changes successful
+            config.accept(path, &original_content, &transformed_content)?;
+            println!("{} successfully for {}", config.applied(), path.display());
+            return Ok(());
+        } else {
+            // Write the transformed content to the file
+            config.stage(path, Some(&original_content), Some(&transformed_content))?;
+
+            let check = Check::run(config, validate_command.as_deref(), baseline)?.ok_or("Nothing to validate with")?;
+            let Check { outcome, passed, diagnostics, .. } = &check;
+            let (passed, diagnostics) = (*passed, *diagnostics);
+            if passed && (strategy != RetryStrategy::BestOf || diagnostics == 0) {
+                config.accept(path, &original_content, &transformed_content)?;
+                println!(
+                    "{} and validated for {} (attempt {}, {} strategy)",
+                    config.applied(),
+                    path.display(),
+                    attempt + 1,
+                    strategy.name()
+                );
+                return Ok(());
+            }
+
+            if passed {
+                println!("Validation passed for {} with {} diagnostics, looking for a cleaner result...", path.display(), diagnostics);
+                if best.as_ref().is_none_or(|(fewest, _, _)| diagnostics < *fewest) {
+                    best = Some((diagnostics, attempt, transformed_content.clone()));
+                }
+            } else if outcome.timed_out.is_some() {
+                println!("Validation timed out for {} ({}), retrying...", path.display(), outcome.summary(path));
+            } else if check.behaviour {
+                println!("Behaviour changed for {} ({} tests differ), retrying...", path.display(), outcome.diagnostics.len());
+            } else if check.baseline.is_some() {
+                println!(
+                    "Validation failed for {} with {} new diagnostics ({}), retrying...",
+                    path.display(),
+                    diagnostics,
+                    outcome.summary(path)
+                );
+            } else {
+                println!("Validation failed for {} ({}), retrying...", path.display(), outcome.summary(path));
+            }
+            check.feedback(&diff)
+        };
+
+        match strategy {
+            RetryStrategy::Iterative => {
+                conversation.push(ChatMessage::user(format!(
+                    "{}\n\nThe file now contains your previous attempt; answer relative to it.",
+                    attempt_feedback
+                )));
+                base_content = transformed_content;
+            }
+            // Every attempt starts from the original file
+            RetryStrategy::Fresh | RetryStrategy::BestOf => config.stage(path, Some(&original_content), Some(&original_content))?,
+        }
+        feedback = Some(attempt_feedback);
+    }
+
+    if let Some((diagnostics, attempt, content)) = best {
+        config.stage(path, Some(&original_content), Some(&content))?;
+        config.accept(path, &original_content, &content)?;
+        println!(
+            "{} and validated for {} (attempt {}, {} strategy, {} diagnostics)",
+            config.applied(),
+            path.display(),
+            attempt + 1,
+            strategy.name(),
+            diagnostics
+        );
+        return Ok(());
+    }
+
+    // Restore original content after final retry; an isolated run never touched the live file
+    if config.reset_staged(path, &original_content)? && config.scratch.is_none() {
+        println!("Restored original content for {}", path.display());
+    }
+
+    Err("Exceeded retry limit".into())
+}
+
+/// Like `process_file`, but sends every file in one request so the model can
+/// change several files at once, including creating, deleting and renaming.
+pub async fn process_batch(paths: &[PathBuf], provider: &dyn Provider, config: &RunConfig) -> Result<(), Box<dyn Error>> {
+    let files = paths
+        .iter()
+        .map(|path| Ok((path.clone(), fs::read_to_string(path)?)))
+        .collect::<Result<Vec<_>, Box<dyn Error>>>()?;
+    let label = format!("{} files", files.len());
+    let validate_command = match &config.validators {
+        Some(validators) => validators.batch_command(paths)?,
+        None => None,
+    };
+    let baseline = validate_command.as_ref().and_then(|command| config.baseline.get(command));
+    let mut feedback: Option<String> = None;
+
+    for attempt in 0..config.n_retries {
+        println!("Processing {} (attempt {}/{})", label, attempt + 1, config.n_retries);
+
+        let request = ChatRequest {
+            model: config.model.clone(),
+            messages: multi_file::messages(&config.instruction, &files, feedback.as_deref()),
+            response_schema: None,
+        };
+        let response = send_request(provider, &request, &label).await?;
+        if response.finish_reason == FinishReason::Length {
+            eprintln!("Could not apply response for {}: {}", label, Truncated);
+            feedback = Some(guard::truncated_prompt());
+            continue;
+        }
+
+        let mut change_set = match multi_file::parse(&response.content)
+            .and_then(|operations| {
+                for operation in &operations {
+                    println!("  {}", operation.describe());
+                }
+                ChangeSet::build(&files, &operations)
+            }) {
+            Ok(change_set) => change_set,
+            Err(e) => {
+                eprintln!("Could not apply response for {}: {}", label, e);
+                feedback = Some(guard::unusable_prompt(&*e));
+                continue;
+            }
+        };
+        if change_set.is_empty() {
+            println!("No changes needed for {}", label);
+            return Ok(());
+        }
+
+        if let Some(prompt) = check_change_set(config, &change_set, &label) {
+            feedback = Some(prompt);
+            continue;
+        }
+
+        if config.interactive {
+            match review_change_set(&mut change_set)? {
+                Some(prompt) => {
+                    println!("Asking the model to redo the change for {}", label);
+                    feedback = Some(prompt);
+                    continue;
+                }
+                None if change_set.is_empty() => {
+                    println!("No changes kept for {}", label);
+                    return Ok(());
+                }
+                // Skipped and edited hunks are held to the same checks as the model's answer
+                None => {
+                    if let Some(prompt) = check_change_set(config, &change_set, &label) {
+                        feedback = Some(prompt);
+                        continue;
+                    }
+                }
+            }
+        }
+
+        // If there is nothing to check, consider the changes successful
+        if !config.checks_anything(validate_command.as_deref()) {
+            config.accept_change_set(&change_set)?;
+            println!("{} successfully for {}", config.applied(), label);
+            return Ok(());
+        }
+        for (path, before, after) in change_set.changes() {
+            config.stage(path, before, after)?;
+        }
+
+        let check = Check::run(config, validate_command.as_deref(), baseline)?.ok_or("Nothing to validate with")?;
+        if check.passed {
+            config.accept_change_set(&change_set)?;
+            println!("{} and validated for {}", config.applied(), label);
+            return Ok(());
+        }
+        feedback = Some(check.feedback(&change_set.diff()));
+        // Every attempt starts from the original files
+        if check.outcome.timed_out.is_some() {
+            println!("Validation timed out for {}, retrying...", label);
+        } else if check.behaviour {
+            println!("Behaviour changed for {}, retrying...", label);
+        } else {
+            println!("Validation failed for {}, retrying...", label);
+        }
+        for (path, before, _) in change_set.changes() {
+            config.stage(path, before, before)?;
+        }
+        if attempt == config.n_retries - 1 && config.scratch.is_none() {
+            println!("Restored original content for {}", label);
+        }
+    }
+
+    Err("Exceeded retry limit".into())
+}
+
+/// Requests a candidate for every file without validating, then applies them
+/// all and validates once. If that fails, the changed files are bisected for
+/// the smallest set that still breaks validation; `run_baseline` must have
+/// made sure validation passes without any of them. The other candidates are
+/// validated together once more and kept, and only the culprits, and files
+/// whose first answer could not be used or failed a check, are retried one by
+/// one with `process_file`.
+pub async fn process_bisect(paths: &[PathBuf], provider: &dyn Provider, config: &RunConfig) -> Result<(), Box<dyn Error>> {
+    let mut candidates = Vec::new();
+    // Files whose first answer was unusable or failed a check go through `process_file` afterwards
+    let mut retries = Vec::new();
+    for path in paths {
+        let label = path.display().to_string();
+        let original = match fs::read_to_string(path) {
+            Ok(original) => original,
+            Err(e) => {
+                eprintln!("Error processing file {}: {}", label, e);
+                config.mark(path, FileStatus::Failed)?;
+                continue;
+            }
+        };
+        println!("Processing file {}", label);
+
+        let mut format = config.output_format;
+        let mut conversation = format.messages(&config.instruction, &original, None);
+        match request_with_fallback(&label, provider, config, &mut format, &mut conversation, &original, None).await {
+            Ok(Ok(candidate)) if candidate == original => {
+                println!("No changes needed for {}", label);
+                config.mark(path, FileStatus::Skipped)?;
+            }
+            Ok(Ok(candidate)) if !guard::check(&original, &candidate, config.allow_shrink).is_empty() => {
+                println!("Refusing to write incomplete content for {}; it will be retried on its own", label);
+                retries.push(path.clone());
+            }
+            Ok(Ok(candidate)) if !config.assertions.check(&original, &candidate).is_empty() => {
+                println!("Assertions failed for {}; it will be retried on its own", label);
+                retries.push(path.clone());
+            }
+            Ok(Ok(candidate)) => candidates.push(Candidate {
+                path: path.clone(),
+                original,
+                candidate,
+            }),
+            Ok(Err(e)) => {
+                eprintln!("Could not apply response for {}: {}; it will be retried on its own", label, e);
+                retries.push(path.clone());
+            }
+            Err(e) => {
+                eprintln!("Error processing file {}: {}", label, e);
+                config.mark(path, FileStatus::Failed)?;
+            }
+        }
+    }
+    if candidates.is_empty() {
+        return retry_separately(&retries, provider, config).await;
+    }
+
+    let command = match &config.validators {
+        Some(validators) => validators.batch_command(paths)?,
+        None => None,
+    };
+    if !config.checks_anything(command.as_deref()) {
+        for candidate in &candidates {
+            config.accept(&candidate.path, &candidate.original, &candidate.candidate)?;
+            println!("{} successfully for {}", config.applied(), candidate.path.display());
+        }
+        return retry_separately(&retries, provider, config).await;
+    };
+
+    let mut bisect = Bisect {
+        config,
+        command: command.as_deref(),
+        baseline: command.as_ref().and_then(|command| config.baseline.get(command)),
+        candidates: &candidates,
+        runs: 0,
+    };
+    let everything: Vec<usize> = (0..candidates.len()).collect();
+    // The baseline run made sure validation passes with none of the candidates
+    let culprits = if bisect.passes(&everything)? {
+        Vec::new()
+    } else {
+        let culprits = bisect.culprits(&everything, &[])?;
+        println!(
+            "Bisected {} changed files in {} validation runs; validation is broken by: {}",
+            candidates.len(),
+            bisect.runs,
+            culprits
+                .iter()
+                .map(|&index| candidates[index].path.display().to_string())
+                .collect::<Vec<_>>()
+                .join(", ")
+        );
+        culprits
+    };
+
+    // Keep everything but the culprits, which go back to their original content
+    let mut accepted: Vec<usize> = everything.into_iter().filter(|index| !culprits.contains(index)).collect();
+    // Bisection only tried subsets, so what is left is checked as a whole
+    if !culprits.is_empty() && !accepted.is_empty() && !bisect.passes(&accepted)? {
+        println!("Validation still fails without the culprits; every changed file will be retried on its own");
+        retries.extend(accepted.drain(..).map(|index| candidates[index].path.clone()));
+    }
+    bisect.stage(&accepted)?;
+    for &index in &accepted {
+        let Candidate { path, original, candidate } = &candidates[index];
+        config.accept(path, original, candidate)?;
+        println!("{} and validated for {} (batch)", config.applied(), path.display());
+    }
+
+    retries.extend(culprits.iter().map(|&index| candidates[index].path.clone()));
+    retry_separately(&retries, provider, config).await
+}
+
+// Reports why the candidate for `label` was not written. The feedback for the model.
+fn reject_incomplete(label: &str, problems: &[String]) -> String {
+    println!("Refusing to write incomplete content for {}, retrying...", label);
+    for problem in problems {
+        println!("  {}", problem);
+    }
+    guard::feedback_prompt(problems)
+}
+
+// Reports files of `change_set` that are incomplete or fail an assertion. The feedback for
+// the model if there are any.
+fn check_change_set(config: &RunConfig, change_set: &ChangeSet, label: &str) -> Option<String> {
+    // Never write an answer that leaves parts of a file out
+    let problems: Vec<String> = change_set
+        .changes()
+        .filter_map(|(path, before, after)| Some((path, before.unwrap_or(""), after?)))
+        .flat_map(|(path, before, after)| {
+            guard::check(before, after, config.allow_shrink)
+                .into_iter()
+                .map(move |problem| format!("{}: {}", path.display(), problem))
+        })
+        .collect();
+    if !problems.is_empty() {
+        return Some(reject_incomplete(label, &problems));
+    }
+
+    // Deleted files have nothing left to check
+    let failures: Vec<String> = change_set
+        .changes()
+        .filter_map(|(path, before, after)| Some((path, before.unwrap_or(""), after?)))
+        .flat_map(|(path, before, after)| {
+            config
+                .assertions
+                .check(before, after)
+                .into_iter()
+                .map(move |failure| format!("{}: {}", path.display(), failure))
+        })
+        .collect();
+    if !failures.is_empty() {
+        println!("Assertions failed for {}, retrying...", label);
+        for failure in &failures {
+            println!("  {}", failure);
+        }
+        return Some(assertions::feedback_prompt(&change_set.diff(), &failures));
+    }
+    None
+}
+
+// Reviews every file of `change_set`, keeping what the reviewer applied. The
+// feedback for the model if the reviewer sent the change back.
+fn review_change_set(change_set: &mut ChangeSet) -> Result<Option<String>, Box<dyn Error>> {
+    let changes: Vec<(PathBuf, Option<String>, Option<String>)> = change_set
+        .changes()
+        .map(|(path, before, after)| (path.to_path_buf(), before.map(String::from), after.map(String::from)))
+        .collect();
+    for (path, before, after) in changes {
+        let original = before.as_deref().unwrap_or("");
+        match review::review(&path, original, after.as_deref().unwrap_or(""))? {
+            Verdict::Keep(content) if content == original => change_set.revise(&path, before),
+            // Created and deleted files only have one hunk
+            Verdict::Keep(content) if after.is_none() && content.is_empty() => {}
+            Verdict::Keep(content) => change_set.revise(&path, Some(content)),
+            Verdict::Redo(prompt) => return Ok(Some(prompt)),
+        }
+    }
+    Ok(None)
+}
+
+async fn retry_separately(paths: &[PathBuf], provider: &dyn Provider, config: &RunConfig) -> Result<(), Box<dyn Error>> {
+    for path in paths {
+        println!("Retrying {} on its own", path.display());
+        match process_file(path, provider, config).await {
+            Ok(()) => {}
+            Err(e) if e.is::<Interrupted>() => return Err(e),
+            Err(e) => {
+                eprintln!("Error processing file {}: {}", path.display(), e);
+                config.mark(path, FileStatus::Failed)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// The verdict on a staged candidate.
+struct Check<'a> {
+    /// The command whose outcome decided the verdict.
+    command: &'a str,
+    outcome: ValidationOutcome,
+    /// What `outcome` was judged against.
+    baseline: Option<&'a ValidationOutcome>,
+    /// Whether the verdict comes from the behaviour-equivalence tests.
+    behaviour: bool,
+    passed: bool,
+    diagnostics: usize,
+}
+
+impl<'a> Check<'a> {
+    // Runs the validation command, then the behaviour-equivalence tests if it passed.
+    // `None` when there is nothing to run.
+    fn run(config: &'a RunConfig, command: Option<&'a str>, baseline: Option<&'a ValidationOutcome>) -> Result<Option<Self>, Box<dyn Error>> {
+        let dir = config.validation_dir();
+        let mut check = None;
+        if let Some(command) = command {
+            let outcome = validation::validate_change(command, config.diagnostics_format, dir, &config.validation_limits)?;
+            let (passed, diagnostics) = outcome.judge(baseline);
+            check = Some(Check {
+                command,
+                outcome,
+                baseline,
+                behaviour: false,
+                passed,
+                diagnostics,
+            });
+        }
+
+        let Some(equivalence) = &config.equivalence else {
+            return Ok(check);
+        };
+        if check.as_ref().is_some_and(|check| !check.passed) {
+            return Ok(check);
+        }
+        let outcome = equivalence.check(dir, &config.validation_limits)?;
+        if outcome.success && check.is_some() {
+            return Ok(check);
+        }
+        Ok(Some(Check {
+            command: &equivalence.command,
+            passed: outcome.success,
+            diagnostics: outcome.diagnostics.len(),
+            outcome,
+            baseline: None,
+            behaviour: true,
+        }))
+    }
+
+    fn feedback(&self, diff: &str) -> String {
+        validation::feedback_prompt(self.command, diff, &self.outcome, self.baseline)
+    }
+}
+
+struct Candidate {
+    path: PathBuf,
+    original: String,
+    candidate: String,
+}
+
+// Validates subsets of a batch of candidates
+struct Bisect<'a> {
+    config: &'a RunConfig,
+    command: Option<&'a str>,
+    baseline: Option<&'a ValidationOutcome>,
+    candidates: &'a [Candidate],
+    runs: usize,
+}
+
+impl Bisect<'_> {
+    // Writes the candidates in `applied` and the original content of every other file
+    fn stage(&self, applied: &[usize]) -> Result<(), Box<dyn Error>> {
+        for (index, candidate) in self.candidates.iter().enumerate() {
+            let content = if applied.contains(&index) { &candidate.candidate } else { &candidate.original };
+            self.config.stage(&candidate.path, Some(&candidate.original), Some(content))?;
+        }
+        Ok(())
+    }
+
+    fn passes(&mut self, applied: &[usize]) -> Result<bool, Box<dyn Error>> {
+        println!("Validating {} of {} changed files together", applied.len(), self.candidates.len());
+        self.stage(applied)?;
+        self.runs += 1;
+        let check = Check::run(self.config, self.command, self.baseline)?;
+        Ok(check.is_none_or(|check| check.passed))
+    }
+
+    // The smallest part of `suspects` that breaks validation on top of `fixed`, which passes
+    // on its own while `fixed` plus all of `suspects` fails. When neither half fails alone
+    // the failure comes from an interaction, and each half is searched with the other applied.
+    fn culprits(&mut self, suspects: &[usize], fixed: &[usize]) -> Result<Vec<usize>, Box<dyn Error>> {
+        if suspects.len() == 1 {
+            return Ok(suspects.to_vec());
+        }
+        let (left, right) = suspects.split_at(suspects.len() / 2);
+        let with = |part: &[usize]| [fixed, part].concat();
+
+        if !self.passes(&with(left))? {
+            return self.culprits(left, fixed);
+        }
+        if !self.passes(&with(right))? {
+            return self.culprits(right, fixed);
+        }
+        let mut culprits = self.culprits(left, &with(right))?;
+        culprits.extend(self.culprits(right, &with(left))?);
+        Ok(culprits)
+    }
+}
+
+/// `request_change` in `*format`, switching to a full rewrite of `content`
+/// when the answer cannot be applied and `--full-fallback` is set.
+async fn request_with_fallback(
+    label: &str,
+    provider: &dyn Provider,
+    config: &RunConfig,
+    format: &mut OutputFormat,
+    messages: &mut Vec<ChatMessage>,
+    content: &str,
+    feedback: Option<&str>,
+) -> Result<Result<String, Box<dyn Error>>, Box<dyn Error>> {
+    let result = request_change(label, provider, config, *format, messages, content).await?;
+    match &result {
+        // A full rewrite would only be cut off sooner
+        Err(e) if *format != OutputFormat::Full && config.full_fallback && !e.is::<Truncated>() => {
+            eprintln!("Could not apply response for {}: {}", label, e);
+            println!("Falling back to a full rewrite of {}", label);
+            *format = OutputFormat::Full;
+            *messages = format.messages(&config.instruction, content, feedback);
+            request_change(label, provider, config, *format, messages, content).await
+        }
+        _ => Ok(result),
+    }
+}
+
+/// Sends `messages` and applies the answer to `content`, asking the model to
+/// redo edits that could not be applied up to `MAX_REPAIRS` times. Every
+/// exchange is appended to `messages`. The outer error is a failed request;
+/// the inner one an answer that could not be used.
+async fn request_change(
+    label: &str,
+    provider: &dyn Provider,
+    config: &RunConfig,
+    output_format: OutputFormat,
+    messages: &mut Vec<ChatMessage>,
+    content: &str,
+) -> Result<Result<String, Box<dyn Error>>, Box<dyn Error>> {
+    let mut content = content.to_string();
+
+    for repair in 0..=MAX_REPAIRS {
+        let request = ChatRequest {
+            model: config.model.clone(),
+            messages: messages.clone(),
+            response_schema: output_format.response_schema(),
+        };
+
+        let response = send_request(provider, &request, label).await?;
+        let output = match complete_answer(label, provider, config, output_format, &request, response).await? {
+            Ok(output) => output,
+            Err(e) => return Ok(Err(e)),
+        };
+        match output_format.apply(&output, &content) {
+            Ok(Applied::Complete(applied)) => {
+                messages.push(ChatMessage::assistant(output));
+                return Ok(Ok(applied));
+            }
+            Ok(Applied::Partial { content: partial, repair_prompt }) => {
+                if repair == MAX_REPAIRS {
+                    break;
+                }
+                println!("Some edits did not apply to {}, asking the model to redo them", label);
+                messages.push(ChatMessage::assistant(output));
+                messages.push(ChatMessage::user(repair_prompt));
+                content = partial;
+            }
+            Err(e) => return Ok(Err(e)),
+        }
+    }
+
+    Ok(Err(format!("Edits still did not apply after {} repair requests", MAX_REPAIRS).into()))
+}
+
+/// Returns the answer in `response` to `request`, asking for the rest of it
+/// up to `max_continuations` times while it is cut off at the output token
+/// limit. The inner error is an answer that stayed incomplete.
+async fn complete_answer(
+    label: &str,
+    provider: &dyn Provider,
+    config: &RunConfig,
+    output_format: OutputFormat,
+    request: &ChatRequest,
+    response: ChatResponse,
+) -> Result<Result<String, Box<dyn Error>>, Box<dyn Error>> {
+    let mut output = response.content;
+    let mut finish_reason = response.finish_reason;
+    let mut continuations = 0;
+
+    while finish_reason == FinishReason::Length {
+        // Whatever was cut off cannot be trusted, even if it happens to parse
+        let Some(tag) = output_format.answer_tag().filter(|_| continuations < config.max_continuations) else {
+            return Ok(Err(Box::new(Truncated)));
+        };
+        continuations += 1;
+        println!(
+            "Response for {} was cut off, requesting continuation {}/{}",
+            label, continuations, config.max_continuations
+        );
+
+        let mut messages = request.messages.clone();
+        messages.push(ChatMessage::assistant(output.clone()));
+        messages.push(ChatMessage::user(guard::CONTINUE_PROMPT));
+        let continuation = ChatRequest {
+            model: request.model.clone(),
+            messages,
+            response_schema: None,
+        };
+        let response = send_request(provider, &continuation, label).await?;
+        output = match guard::stitch(&output, &response.content, tag) {
+            Ok(stitched) => stitched,
+            Err(e) => return Ok(Err(e)),
+        };
+        finish_reason = response.finish_reason;
+    }
+
+    if let Some(tag) = output_format.answer_tag().filter(|_| continuations > 0) {
+        if let Err(e) = guard::check_stitched(&output, tag) {
+            return Ok(Err(e));
+        }
+        println!("Stitched the answer for {} together from {} parts", label, continuations + 1);
+    }
+    Ok(Ok(output))
+}
+
+/// Sends `request`, reporting token usage and truncated answers for `label`.
+async fn send_request(provider: &dyn Provider, request: &ChatRequest, label: &str) -> Result<ChatResponse, Box<dyn Error>> {
+    let response = provider.chat(request).await?;
+    if let (Some(input), Some(output)) = (response.usage.input_tokens, response.usage.output_tokens) {
+        println!("{} usage: {} input tokens, {} output tokens", provider.name(), input, output);
+    }
+    if response.finish_reason == FinishReason::Length {
+        eprintln!("Warning: response for {} hit the output token limit and may be truncated", label);
+    }
+    Ok(response)
+}
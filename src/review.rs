@@ -0,0 +1,148 @@
+use std::env;
+use std::error::Error;
+use std::fs;
+use std::io::{self, BufRead, Write};
+use std::ops::Range;
+use std::path::Path;
+use std::process::Command;
+
+use similar::TextDiff;
+
+use crate::dry_run;
+use crate::interrupt;
+use crate::validation;
+use crate::workspace;
+
+// Lines of unchanged context around each hunk; nearby changes share a hunk
+const CONTEXT_LINES: usize = 3;
+
+const HELP: &str = "y - apply this hunk
+n - do not apply this hunk
+e - edit the new version of this hunk in $EDITOR
+r - ask the model to redo the change, with a comment about this hunk
+a - apply this hunk and all later hunks in the file
+d - do not apply this hunk or any later hunk in the file
+? - print help";
+
+/// What the reviewer made of a candidate.
+pub enum Verdict {
+    /// The content to go on with: the original with the hunks the reviewer
+    /// applied or edited.
+    Keep(String),
+    /// The reviewer sent the change back; the feedback for the model.
+    Redo(String),
+}
+
+enum Choice {
+    Apply,
+    Skip,
+    Replace(String),
+}
+
+/// Shows the change from `original` to `candidate` hunk by hunk, `git add
+/// -p` style, and asks what to do with each.
+pub fn review(path: &Path, original: &str, candidate: &str) -> Result<Verdict, Box<dyn Error>> {
+    let diff = TextDiff::from_lines(original, candidate);
+    let (old, new) = (diff.old_slices(), diff.new_slices());
+    let mut unified = diff.unified_diff();
+    let hunks: Vec<(String, Range<usize>, Range<usize>)> = unified
+        .context_radius(CONTEXT_LINES)
+        .iter_hunks()
+        .map(|hunk| {
+            let (first, last) = (&hunk.ops()[0], &hunk.ops()[hunk.ops().len() - 1]);
+            (
+                hunk.to_string(),
+                first.old_range().start..last.old_range().end,
+                first.new_range().start..last.new_range().end,
+            )
+        })
+        .collect();
+
+    println!("{}", dry_run::colorize(&format!("--- a/{0}\n+++ b/{0}", path.display())));
+    let mut choices = Vec::new();
+    // Set by `a` and `d` for the rest of the file
+    let mut rest: Option<bool> = None;
+    for (index, (text, _, new_range)) in hunks.iter().enumerate() {
+        if let Some(apply) = rest {
+            choices.push(if apply { Choice::Apply } else { Choice::Skip });
+            continue;
+        }
+        print!("{}", dry_run::colorize(text));
+        loop {
+            let answer = ask(&format!("({}/{}) Apply this hunk to {} [y,n,e,r,a,d,?]? ", index + 1, hunks.len(), path.display()))?;
+            match answer.as_str() {
+                "y" => choices.push(Choice::Apply),
+                "n" => choices.push(Choice::Skip),
+                "a" | "d" => {
+                    rest = Some(answer == "a");
+                    choices.push(if answer == "a" { Choice::Apply } else { Choice::Skip });
+                }
+                "e" => choices.push(Choice::Replace(edit(path, &new[new_range.clone()].concat())?)),
+                "r" => {
+                    let comment = ask("Comment for the model: ")?;
+                    return Ok(Verdict::Redo(redo_prompt(path, original, candidate, text, &comment)));
+                }
+                _ => {
+                    println!("{}", HELP);
+                    continue;
+                }
+            }
+            break;
+        }
+    }
+
+    let mut content = String::new();
+    let mut position = 0;
+    for ((_, old_range, new_range), choice) in hunks.iter().zip(choices) {
+        content.push_str(&old[position..old_range.start].concat());
+        match choice {
+            Choice::Apply => content.push_str(&new[new_range.clone()].concat()),
+            Choice::Skip => content.push_str(&old[old_range.clone()].concat()),
+            Choice::Replace(replacement) => content.push_str(&replacement),
+        }
+        position = old_range.end;
+    }
+    content.push_str(&old[position..].concat());
+    Ok(Verdict::Keep(content))
+}
+
+fn ask(prompt: &str) -> Result<String, Box<dyn Error>> {
+    print!("{}", prompt);
+    io::stdout().flush()?;
+    // Read on another thread so Ctrl-C does not wait for the user to press Enter
+    let (read, answer) = interrupt::blocking(|| {
+        let mut answer = String::new();
+        (io::stdin().lock().read_line(&mut answer), answer)
+    })?;
+    if read? == 0 {
+        return Err("Standard input closed during review".into());
+    }
+    Ok(answer.trim().to_string())
+}
+
+// Opens the proposed lines of a hunk in $EDITOR and returns what was saved
+fn edit(path: &Path, lines: &str) -> Result<String, Box<dyn Error>> {
+    let extension = path.extension().map_or(String::new(), |extension| format!(".{}", extension.to_string_lossy()));
+    let file = env::temp_dir().join(format!("{}hunk-{}{}", workspace::TEMP_PREFIX, std::process::id(), extension));
+    fs::write(&file, lines)?;
+    println!("Edit the new version of this hunk; the saved file replaces the lines it removes");
+
+    let editor = env::var("VISUAL").or_else(|_| env::var("EDITOR")).unwrap_or_else(|_| "vi".to_string());
+    // The editor may come with arguments of its own
+    let status = Command::new("sh").arg("-c").arg(format!("{} \"$1\"", editor)).arg("sh").arg(&file).status();
+    let edited = fs::read_to_string(&file);
+    fs::remove_file(&file)?;
+    if !status?.success() {
+        return Err(format!("Editor `{}` failed", editor).into());
+    }
+    Ok(edited?)
+}
+
+fn redo_prompt(path: &Path, original: &str, candidate: &str, hunk: &str, comment: &str) -> String {
+    format!(
+        "<PREVIOUS_ATTEMPT>\nA reviewer sent your previous attempt back. It made the changes below.\n\n<PREVIOUS_DIFF>\n{}</PREVIOUS_DIFF>\n\nThey asked you to redo this part of it:\n\n<HUNK>\n{}</HUNK>\n\nReviewer's comment: {}\n\nAnswer again with the whole change, following the instruction and the comment.\n</PREVIOUS_ATTEMPT>",
+        validation::unified_diff(&path.display().to_string(), original, candidate),
+        hunk,
+        comment
+    )
+}
@@ -0,0 +1,163 @@
+use std::collections::BTreeMap;
+use std::fmt;
+use std::sync::LazyLock;
+
+use regex::Regex;
+
+use crate::diagnostics::{self, xml_attribute};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TestStatus {
+    Passed,
+    Failed,
+    Skipped,
+}
+
+impl fmt::Display for TestStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            TestStatus::Passed => "passed",
+            TestStatus::Failed => "failed",
+            TestStatus::Skipped => "skipped",
+        })
+    }
+}
+
+/// The outcome of every test in one run, by test name.
+pub type TestResults = BTreeMap<String, TestStatus>;
+
+static TAP_RESULT: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(r"^\s*(?P<not>not )?ok\b(?:\s+(?P<number>\d+))?(?:\s*-)?\s*(?P<description>[^#]*?)\s*(?:#\s*(?P<directive>\w+).*)?$").unwrap()
+});
+
+/// Reads per-test results from JUnit XML or, failing that, TAP output.
+/// Empty if the output contains neither.
+pub fn parse(output: &str) -> TestResults {
+    let results = parse_junit(output);
+    if !results.is_empty() {
+        return results;
+    }
+    parse_tap(output)
+}
+
+/// Every way `after` behaves differently from `before`: tests whose outcome
+/// changed and passing tests that no longer run.
+pub fn differences(before: &TestResults, after: &TestResults) -> Vec<String> {
+    let mut differences = Vec::new();
+    for (name, old) in before {
+        match after.get(name) {
+            Some(new) if new != old => differences.push(format!("{}: {} before the change, {} after it", name, old, new)),
+            None if *old == TestStatus::Passed => differences.push(format!("{}: passed before the change, missing after it", name)),
+            _ => {}
+        }
+    }
+    differences
+}
+
+fn parse_junit(output: &str) -> TestResults {
+    let mut results = TestResults::new();
+    for case in diagnostics::junit_testcases(output) {
+        let name = xml_attribute(case.attrs, "name").unwrap_or_default();
+        let name = match xml_attribute(case.attrs, "classname").filter(|class| !class.is_empty()) {
+            Some(class) => format!("{}::{}", class, name),
+            None => name,
+        };
+
+        let status = if case.failed() {
+            TestStatus::Failed
+        } else if case.skipped() {
+            TestStatus::Skipped
+        } else {
+            TestStatus::Passed
+        };
+        insert_unique(&mut results, name, status);
+    }
+    results
+}
+
+fn parse_tap(output: &str) -> TestResults {
+    let mut results = TestResults::new();
+    for line in output.lines() {
+        let Some(caps) = TAP_RESULT.captures(line) else {
+            continue;
+        };
+        let name = match (caps.name("description").map(|m| m.as_str()), caps.name("number")) {
+            (Some(description), _) if !description.is_empty() => description.to_string(),
+            (_, Some(number)) => format!("test {}", number.as_str()),
+            _ => format!("test {}", results.len() + 1),
+        };
+        let skipped = caps.name("directive").is_some_and(|directive| directive.as_str().eq_ignore_ascii_case("skip"));
+        let status = if skipped {
+            TestStatus::Skipped
+        } else if caps.name("not").is_some() {
+            TestStatus::Failed
+        } else {
+            TestStatus::Passed
+        };
+        insert_unique(&mut results, name, status);
+    }
+    results
+}
+
+// Tests with the same name are told apart by the order they ran in
+fn insert_unique(results: &mut TestResults, name: String, status: TestStatus) {
+    let mut unique = name.clone();
+    let mut count = 1;
+    while results.contains_key(&unique) {
+        count += 1;
+        unique = format!("{} ({})", name, count);
+    }
+    results.insert(unique, status);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_tap_output() {
+        let output = "\
+TAP version 13
+1..6
+ok 1 - adds numbers
+not ok 2 - divides by zero
+  ---
+  message: 'expected error'
+  ...
+ok 3 # SKIP no network
+ok 4
+ok - adds numbers
+    ok 1 - nested subtest
+# tests 6
+# okay, that is all
+Bail out! Database unavailable
+";
+        let results = parse_tap(output);
+        let expected = [
+            ("adds numbers", TestStatus::Passed),
+            ("adds numbers (2)", TestStatus::Passed),
+            ("divides by zero", TestStatus::Failed),
+            ("nested subtest", TestStatus::Passed),
+            ("test 3", TestStatus::Skipped),
+            ("test 4", TestStatus::Passed),
+        ];
+        assert_eq!(results, expected.into_iter().map(|(name, status)| (name.to_string(), status)).collect());
+    }
+
+    #[test]
+    fn junit_takes_precedence_over_tap() {
+        let output = "ok 1 - from tap\n<testcase classname=\"suite\" name=\"from_junit\"/>";
+        assert_eq!(parse(output).into_keys().collect::<Vec<_>>(), ["suite::from_junit"]);
+        assert!(parse("no results here\n").is_empty());
+    }
+
+    #[test]
+    fn reports_changed_and_missing_tests() {
+        let before = parse_tap("ok 1 - a\nok 2 - b\nnot ok 3 - c\nnot ok 4 - d\n");
+        let after = parse_tap("not ok 1 - a\nok 3 - c\n");
+        assert_eq!(
+            differences(&before, &after),
+            ["a: passed before the change, failed after it", "b: passed before the change, missing after it", "c: failed before the change, passed after it"]
+        );
+    }
+}
@@ -0,0 +1,403 @@
+use std::error::Error;
+use std::io::{self, Read};
+use std::os::unix::process::CommandExt;
+use std::path::Path;
+use std::process::{Command as ProcessCommand, Stdio};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use similar::TextDiff;
+
+use crate::diagnostics::{Diagnostic, DiagnosticsFormat, Severity};
+use crate::interrupt::{self, Interrupted};
+use crate::test_results::{self, TestResults, TestStatus};
+
+// Budget for validation output quoted back to the model. Compilers and test
+// runners put the first error near the top, so most of it goes to the head.
+const FEEDBACK_HEAD_CHARS: usize = 3000;
+const FEEDBACK_TAIL_CHARS: usize = 1000;
+// At most this many parsed diagnostics are listed in a feedback prompt
+const FEEDBACK_MAX_DIAGNOSTICS: usize = 30;
+// How often a running validation command is checked against its timeout and for Ctrl-C
+const POLL_INTERVAL: Duration = Duration::from_millis(50);
+// Time between SIGTERM and SIGKILL when a validation command is killed
+const KILL_GRACE: Duration = Duration::from_secs(2);
+
+/// Resource limits for every validation run.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ValidationLimits {
+    /// Wall-clock time after which the command's process group is killed.
+    pub timeout: Option<Duration>,
+    /// CPU time per process (`RLIMIT_CPU`), in seconds.
+    pub cpu_seconds: Option<u64>,
+    /// Address space per process (`RLIMIT_AS`), in bytes.
+    pub memory_bytes: Option<u64>,
+}
+
+/// The result of running the validation command once.
+pub struct ValidationOutcome {
+    pub success: bool,
+    /// Combined stdout and stderr.
+    pub output: String,
+    /// What the output parser found in `output`.
+    pub diagnostics: Vec<Diagnostic>,
+    /// Set to the timeout when the command was killed for running too long.
+    pub timed_out: Option<Duration>,
+}
+
+/// Runs `command` through `sh -c` in `dir` (the current directory if `None`)
+/// under `limits`, capturing its output and parsing it with `format`. The
+/// output is still echoed so the user sees what the validator printed.
+///
+/// The command runs in its own process group. Whatever it leaves behind in
+/// that group is killed once it exits, and the whole group is killed if it
+/// exceeds the timeout or the run is interrupted, which fails with
+/// `Interrupted`.
+pub fn validate_change(
+    command: &str,
+    format: DiagnosticsFormat,
+    dir: Option<&Path>,
+    limits: &ValidationLimits,
+) -> Result<ValidationOutcome, Box<dyn Error>> {
+    let mut process = ProcessCommand::new("sh");
+    process
+        .arg("-c")
+        .arg(command)
+        // Outside the terminal's foreground group a read from stdin would stop the command
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .process_group(0);
+    if let Some(dir) = dir {
+        process.current_dir(dir);
+    }
+    let (cpu_seconds, memory_bytes) = (limits.cpu_seconds, limits.memory_bytes);
+    if cpu_seconds.is_some() || memory_bytes.is_some() {
+        // SAFETY: the hook only calls setrlimit, which is async-signal-safe
+        unsafe {
+            process.pre_exec(move || {
+                let set_limit = |resource, value: u64| {
+                    let limit = libc::rlimit {
+                        rlim_cur: value as libc::rlim_t,
+                        rlim_max: value as libc::rlim_t,
+                    };
+                    match libc::setrlimit(resource, &limit) {
+                        0 => Ok(()),
+                        _ => Err(io::Error::last_os_error()),
+                    }
+                };
+                if let Some(seconds) = cpu_seconds {
+                    set_limit(libc::RLIMIT_CPU, seconds)?;
+                }
+                if let Some(bytes) = memory_bytes {
+                    set_limit(libc::RLIMIT_AS, bytes)?;
+                }
+                Ok(())
+            });
+        }
+    }
+
+    let mut child = process.spawn()?;
+    let group = child.id() as libc::pid_t;
+    let stdout_reader = read_in_background(child.stdout.take());
+    let stderr_reader = read_in_background(child.stderr.take());
+
+    let started = Instant::now();
+    let mut timed_out = None;
+    let status = loop {
+        if let Some(status) = child.try_wait()? {
+            break status;
+        }
+        let timeout = limits.timeout.filter(|timeout| started.elapsed() >= *timeout);
+        if timeout.is_some() || interrupt::interrupted() {
+            match timeout {
+                Some(timeout) => eprintln!("Validation command timed out after {}s, killing it", timeout.as_secs()),
+                None => eprintln!("Killing the validation command"),
+            }
+            timed_out = timeout;
+            kill_group(group, libc::SIGTERM);
+            let deadline = Instant::now() + KILL_GRACE;
+            while child.try_wait()?.is_none() && Instant::now() < deadline {
+                thread::sleep(POLL_INTERVAL);
+            }
+            kill_group(group, libc::SIGKILL);
+            break child.wait()?;
+        }
+        thread::sleep(POLL_INTERVAL);
+    };
+    // Background processes would otherwise keep the output pipes open
+    kill_group(group, libc::SIGKILL);
+
+    let stdout = String::from_utf8_lossy(&stdout_reader.join().unwrap_or_default()).into_owned();
+    let stderr = String::from_utf8_lossy(&stderr_reader.join().unwrap_or_default()).into_owned();
+    // The outcome of a killed run says nothing about the candidate
+    if interrupt::interrupted() {
+        return Err(Box::new(Interrupted));
+    }
+    print!("{}", stdout);
+    eprint!("{}", stderr);
+
+    let combined = format!("{}{}", stdout, stderr);
+    let mut diagnostics = format.parse(&combined);
+    // Absolute paths into a scratch copy refer to the same files in the live tree
+    if let Some(dir) = dir {
+        for diagnostic in &mut diagnostics {
+            if let Some(relative) = diagnostic.file.as_deref().and_then(|file| file.strip_prefix(dir).ok()) {
+                diagnostic.file = Some(relative.to_path_buf());
+            }
+        }
+    }
+    Ok(ValidationOutcome {
+        success: status.success() && timed_out.is_none(),
+        diagnostics,
+        output: combined,
+        timed_out,
+    })
+}
+
+// Diagnostic code for a test whose outcome differs from the recorded one
+const BEHAVIOUR_CODE: &str = "behaviour";
+
+/// Per-test results of a test command on the untouched tree. A candidate is
+/// behaviour-equivalent if the same command reproduces them exactly.
+pub struct Equivalence {
+    pub command: String,
+    before: TestResults,
+}
+
+impl Equivalence {
+    /// Runs `command` once in `dir` and records the outcome of every test.
+    pub fn record(command: &str, dir: Option<&Path>, limits: &ValidationLimits) -> Result<Self, Box<dyn Error>> {
+        println!("Recording test results of `{}` before any change", command);
+        let outcome = validate_change(command, DiagnosticsFormat::Generic, dir, limits)?;
+        if let Some(timeout) = outcome.timed_out {
+            return Err(format!("Test command `{}` timed out after {}s before any change", command, timeout.as_secs()).into());
+        }
+        let before = test_results::parse(&outcome.output);
+        if before.is_empty() {
+            return Err(format!("No JUnit XML or TAP test results found in the output of `{}`", command).into());
+        }
+        let passed = before.values().filter(|status| **status == TestStatus::Passed).count();
+        println!("Recorded {} tests ({} passing)", before.len(), passed);
+        Ok(Equivalence {
+            command: command.to_string(),
+            before,
+        })
+    }
+
+    /// Runs the test command again on the candidate. The outcome fails, with
+    /// one diagnostic per differing test, unless every test behaves as before.
+    pub fn check(&self, dir: Option<&Path>, limits: &ValidationLimits) -> Result<ValidationOutcome, Box<dyn Error>> {
+        let mut outcome = validate_change(&self.command, DiagnosticsFormat::Generic, dir, limits)?;
+        let after = test_results::parse(&outcome.output);
+        let differences = if after.is_empty() && outcome.timed_out.is_none() {
+            vec!["no test results found in the output".to_string()]
+        } else {
+            test_results::differences(&self.before, &after)
+        };
+
+        outcome.success = outcome.timed_out.is_none() && differences.is_empty();
+        outcome.diagnostics = differences
+            .into_iter()
+            .map(|message| Diagnostic {
+                severity: Severity::Error,
+                file: None,
+                line: None,
+                column: None,
+                code: Some(BEHAVIOUR_CODE.to_string()),
+                message,
+            })
+            .collect();
+        Ok(outcome)
+    }
+}
+
+// Drains a child's pipe on its own thread so a chatty command cannot block on a full pipe
+fn read_in_background(pipe: Option<impl Read + Send + 'static>) -> thread::JoinHandle<Vec<u8>> {
+    thread::spawn(move || {
+        let mut buffer = Vec::new();
+        if let Some(mut pipe) = pipe {
+            let _ = pipe.read_to_end(&mut buffer);
+        }
+        buffer
+    })
+}
+
+fn kill_group(group: libc::pid_t, signal: libc::c_int) {
+    // Fails harmlessly with ESRCH once every process in the group is gone
+    unsafe {
+        libc::killpg(group, signal);
+    }
+}
+
+impl ValidationOutcome {
+    /// Number of errors and warnings, used to rank candidates. Falls back to
+    /// counting suspicious lines when nothing could be parsed. A failed run
+    /// always counts at least one.
+    pub fn diagnostic_count(&self) -> usize {
+        let count = if self.diagnostics.is_empty() {
+            self.output
+                .lines()
+                .map(str::to_lowercase)
+                .filter(|line| line.contains("error") || line.contains("warning"))
+                .count()
+        } else {
+            self.diagnostics
+                .iter()
+                .filter(|diagnostic| diagnostic.severity != Severity::Note)
+                .count()
+        };
+        if self.success {
+            count
+        } else {
+            count.max(1)
+        }
+    }
+
+    /// Whether this run is acceptable, and how many diagnostics count against
+    /// it. With a `baseline` (a failing run on the untouched tree) only the
+    /// diagnostics the change added count, and a failing run passes if it
+    /// added none. A timeout never passes.
+    pub fn judge(&self, baseline: Option<&ValidationOutcome>) -> (bool, usize) {
+        let Some(baseline) = baseline else {
+            return (self.success, self.diagnostic_count());
+        };
+        if self.timed_out.is_some() {
+            return (false, self.diagnostic_count());
+        }
+        let added = if self.diagnostics.is_empty() || baseline.diagnostics.is_empty() {
+            self.diagnostic_count().saturating_sub(baseline.diagnostic_count())
+        } else {
+            self.new_diagnostics(baseline).len()
+        };
+        (self.success || added == 0, added)
+    }
+
+    /// Errors and warnings not already reported by `baseline`. Diagnostics are
+    /// matched on file, severity, code and message, since edits move lines.
+    pub fn new_diagnostics(&self, baseline: &ValidationOutcome) -> Vec<&Diagnostic> {
+        let same = |a: &Diagnostic, b: &Diagnostic| {
+            a.file == b.file && a.severity == b.severity && a.code == b.code && a.message == b.message
+        };
+        let mut known: Vec<&Diagnostic> = baseline.diagnostics.iter().collect();
+        self.diagnostics
+            .iter()
+            .filter(|diagnostic| diagnostic.severity != Severity::Note)
+            .filter(|diagnostic| match known.iter().position(|old| same(old, diagnostic)) {
+                Some(index) => {
+                    known.swap_remove(index);
+                    false
+                }
+                None => true,
+            })
+            .collect()
+    }
+
+    /// One-line account of the diagnostics, saying how many point at `path`.
+    pub fn summary(&self, path: &Path) -> String {
+        if let Some(timeout) = self.timed_out {
+            return format!("timed out after {}s", timeout.as_secs());
+        }
+        let errors = self.diagnostics.iter().filter(|d| d.severity == Severity::Error).count();
+        let warnings = self.diagnostics.iter().filter(|d| d.severity == Severity::Warning).count();
+        let here = self.diagnostics.iter().filter(|d| d.is_in(path)).count();
+        format!(
+            "{} errors, {} warnings, {} in {}",
+            errors,
+            warnings,
+            here,
+            path.display()
+        )
+    }
+}
+
+/// Shortens `output` to fit the feedback budget, keeping whole lines from the
+/// start and the end.
+pub fn truncate_output(output: &str) -> String {
+    if output.len() <= FEEDBACK_HEAD_CHARS + FEEDBACK_TAIL_CHARS {
+        return output.to_string();
+    }
+
+    let mut head_end = 0;
+    for line in output.split_inclusive('\n') {
+        if head_end + line.len() > FEEDBACK_HEAD_CHARS {
+            break;
+        }
+        head_end += line.len();
+    }
+    let mut tail_start = output.len();
+    for line in output.split_inclusive('\n').rev() {
+        if output.len() - tail_start + line.len() > FEEDBACK_TAIL_CHARS || tail_start - line.len() < head_end {
+            break;
+        }
+        tail_start -= line.len();
+    }
+
+    let omitted = output[head_end..tail_start].lines().count();
+    format!(
+        "{}... {} lines omitted ...\n{}",
+        &output[..head_end],
+        omitted,
+        &output[tail_start..]
+    )
+}
+
+/// Unified diff between two versions of `path`.
+pub fn unified_diff(path: &str, before: &str, after: &str) -> String {
+    TextDiff::from_lines(before, after)
+        .unified_diff()
+        .context_radius(3)
+        .header(&format!("a/{}", path), &format!("b/{}", path))
+        .to_string()
+}
+
+/// Explains a rejected attempt to the model: what it changed and what the
+/// validator said about it. Problems already present in `baseline` are left
+/// out of the diagnostics list.
+pub fn feedback_prompt(command: &str, diff: &str, outcome: &ValidationOutcome, baseline: Option<&ValidationOutcome>) -> String {
+    let verdict = match outcome.timed_out {
+        Some(timeout) => format!(
+            "did not finish within {} seconds and was killed. Look for infinite loops, deadlocks or accidentally slower code",
+            timeout.as_secs()
+        ),
+        None if outcome.success => "passed but reported diagnostics".to_string(),
+        None if outcome.diagnostics.iter().any(|d| d.code.as_deref() == Some(BEHAVIOUR_CODE)) => {
+            "shows that tests behave differently than before your change. The change must keep every test's outcome exactly as it was".to_string()
+        }
+        None if baseline.is_some() => "reported problems that were not there before your change".to_string(),
+        None => "failed".to_string(),
+    };
+    let mut prompt = format!(
+        "<PREVIOUS_ATTEMPT>\nYour previous attempt made the changes below, but the validation command `{}` {}. Fix the problems it reports while still following the instruction.\n\n<PREVIOUS_DIFF>\n{}</PREVIOUS_DIFF>\n",
+        command, verdict, diff
+    );
+
+    let diagnostics = match baseline {
+        Some(baseline) => outcome.new_diagnostics(baseline),
+        None => outcome.diagnostics.iter().collect(),
+    };
+    if !diagnostics.is_empty() {
+        prompt.push_str("\n<DIAGNOSTICS>\n");
+        for diagnostic in diagnostics.iter().take(FEEDBACK_MAX_DIAGNOSTICS) {
+            prompt.push_str(&format!("{}\n", diagnostic));
+        }
+        if diagnostics.len() > FEEDBACK_MAX_DIAGNOSTICS {
+            prompt.push_str(&format!("... {} more\n", diagnostics.len() - FEEDBACK_MAX_DIAGNOSTICS));
+        }
+        if baseline.is_some() {
+            prompt.push_str("Problems that were already reported before your change are not listed.\n");
+        }
+        prompt.push_str("</DIAGNOSTICS>\n");
+    }
+    // Machine-readable output (cargo or eslint JSON) adds nothing once parsed
+    let machine_readable = outcome.output.trim_start().starts_with(['{', '[']);
+    if outcome.diagnostics.is_empty() || !machine_readable {
+        prompt.push_str(&format!(
+            "\n<VALIDATION_OUTPUT>\n{}\n</VALIDATION_OUTPUT>\n",
+            truncate_output(&outcome.output).trim_end()
+        ));
+    }
+
+    prompt.push_str("</PREVIOUS_ATTEMPT>");
+    prompt
+}
@@ -0,0 +1,129 @@
+use std::env;
+use std::error::Error;
+use std::fs;
+use std::path::{Component, Path, PathBuf};
+
+use glob::Pattern;
+
+/// Picks the validation command for each file: the first rule of the mapping
+/// file whose pattern matches the file, otherwise `--validate-with`. Commands
+/// may contain `{file}`, `{dir}`, `{stem}` and `{crate}`.
+pub struct Validators {
+    rules: Vec<(Pattern, String)>,
+    default: Option<String>,
+}
+
+impl Validators {
+    /// Reads the `PATTERN = COMMAND` lines of `mapping`, if given. Returns
+    /// `None` when there is nothing to validate with.
+    pub fn new(default: Option<String>, mapping: Option<&Path>) -> Result<Option<Self>, Box<dyn Error>> {
+        let mut rules = Vec::new();
+        if let Some(mapping) = mapping {
+            for (number, line) in fs::read_to_string(mapping)?.lines().enumerate() {
+                let line = line.trim();
+                if line.is_empty() || line.starts_with('#') {
+                    continue;
+                }
+                let location = format!("{}:{}", mapping.display(), number + 1);
+                let (pattern, command) = line
+                    .split_once('=')
+                    .ok_or_else(|| format!("{}: expected `PATTERN = COMMAND`", location))?;
+                let pattern = Pattern::new(pattern.trim()).map_err(|e| format!("{}: {}", location, e))?;
+                rules.push((pattern, command.trim().to_string()));
+            }
+        }
+
+        if rules.is_empty() && default.is_none() {
+            return Ok(None);
+        }
+        Ok(Some(Validators { rules, default }))
+    }
+
+    /// The command that validates `path`, with its placeholders filled in, or
+    /// `None` if no rule covers it.
+    pub fn command_for(&self, path: &Path) -> Result<Option<String>, Box<dyn Error>> {
+        let path = relative(path);
+        self.rules
+            .iter()
+            .find(|(pattern, _)| pattern.matches_path(&path))
+            .map(|(_, command)| command)
+            .or(self.default.as_ref())
+            .map(|template| expand(template, &path))
+            .transpose()
+    }
+
+    /// One command covering all of `paths`: their distinct commands chained
+    /// with `&&`.
+    pub fn batch_command(&self, paths: &[PathBuf]) -> Result<Option<String>, Box<dyn Error>> {
+        let mut commands: Vec<String> = Vec::new();
+        for path in paths {
+            if let Some(command) = self.command_for(path)? {
+                if !commands.contains(&command) {
+                    commands.push(command);
+                }
+            }
+        }
+        Ok(match commands.len() {
+            0 => None,
+            1 => commands.pop(),
+            // The newline keeps a trailing comment in one command from swallowing the `)`
+            _ => Some(commands.iter().map(|command| format!("({}\n)", command)).collect::<Vec<_>>().join(" && ")),
+        })
+    }
+}
+
+// Patterns and placeholders see paths relative to the starting directory, without `./`
+fn relative(path: &Path) -> PathBuf {
+    let path = match env::current_dir() {
+        Ok(cwd) => path.strip_prefix(&cwd).unwrap_or(path),
+        Err(_) => path,
+    };
+    path.components().filter(|c| !matches!(c, Component::CurDir)).collect()
+}
+
+fn expand(template: &str, path: &Path) -> Result<String, Box<dyn Error>> {
+    let dir = match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent.to_path_buf(),
+        _ => PathBuf::from("."),
+    };
+    let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+
+    let mut command = template
+        .replace("{file}", &shell_quote(&path.to_string_lossy()))
+        .replace("{dir}", &shell_quote(&dir.to_string_lossy()))
+        .replace("{stem}", &shell_quote(&stem));
+    if command.contains("{crate}") {
+        command = command.replace("{crate}", &shell_quote(&crate_name(path)?));
+    }
+    Ok(command)
+}
+
+// Name of the Cargo package the file belongs to, from the nearest manifest with a [package] section
+fn crate_name(path: &Path) -> Result<String, Box<dyn Error>> {
+    let file = env::current_dir()?.join(path);
+    for dir in file.ancestors().skip(1) {
+        let Ok(manifest) = fs::read_to_string(dir.join("Cargo.toml")) else {
+            continue;
+        };
+        let mut in_package = false;
+        for line in manifest.lines().map(str::trim) {
+            if line.starts_with('[') {
+                in_package = line == "[package]";
+            } else if in_package {
+                if let Some(value) = line.strip_prefix("name").and_then(|rest| rest.trim_start().strip_prefix('=')) {
+                    return Ok(value.trim().trim_matches('"').to_string());
+                }
+            }
+        }
+    }
+    Err(format!("No Cargo package found for {}", path.display()).into())
+}
+
+fn shell_quote(value: &str) -> String {
+    let plain = |c: char| c.is_ascii_alphanumeric() || "/._-+,:@%=".contains(c);
+    if !value.is_empty() && value.chars().all(plain) {
+        value.to_string()
+    } else {
+        format!("'{}'", value.replace('\'', "'\\''"))
+    }
+}
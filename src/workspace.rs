@@ -0,0 +1,181 @@
+use std::env;
+use std::error::Error;
+use std::fs;
+use std::io;
+use std::path::{Component, Path, PathBuf};
+use std::process::{self, Command as ProcessCommand};
+
+use crate::journal;
+
+/// Start of the name of every temporary file and directory the tool creates.
+pub const TEMP_PREFIX: &str = "refactoring-assistant-";
+
+/// Names accepted by `--isolate`.
+pub const ISOLATION_MODES: &[&str] = &["none", "copy", "worktree"];
+
+/// Where candidates are validated.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IsolationMode {
+    /// In the live working tree.
+    None,
+    /// In a plain copy of the current directory.
+    Copy,
+    /// In a detached git worktree carrying the uncommitted changes.
+    Worktree,
+}
+
+impl IsolationMode {
+    pub fn from_name(name: &str) -> Result<Self, Box<dyn Error>> {
+        match name {
+            "none" => Ok(IsolationMode::None),
+            "copy" => Ok(IsolationMode::Copy),
+            "worktree" => Ok(IsolationMode::Worktree),
+            other => Err(format!("Unknown isolation mode: {}", other).into()),
+        }
+    }
+}
+
+/// A scratch copy of the working tree. Candidates are written and validated
+/// here, so the live checkout only ever receives accepted changes. Removed
+/// when dropped.
+pub struct Scratch {
+    root: PathBuf,
+    /// The scratch equivalent of the directory the tool was started in.
+    workdir: PathBuf,
+    /// Set for worktrees, which git must be told to remove.
+    repo_root: Option<PathBuf>,
+}
+
+impl Scratch {
+    /// Creates the scratch copy for `mode`, or `None` when not isolating.
+    pub fn create(mode: IsolationMode) -> Result<Option<Self>, Box<dyn Error>> {
+        let root = env::temp_dir().join(format!("{}{}", TEMP_PREFIX, process::id()));
+        if root.exists() {
+            fs::remove_dir_all(&root)?;
+        }
+
+        let scratch = match mode {
+            IsolationMode::None => return Ok(None),
+            IsolationMode::Copy => {
+                copy_tree(Path::new("."), &root)?;
+                Scratch { workdir: root.clone(), root, repo_root: None }
+            }
+            IsolationMode::Worktree => create_worktree(root)?,
+        };
+        println!("Validating candidates in scratch copy {}", scratch.workdir.display());
+        Ok(Some(scratch))
+    }
+
+    /// Directory to run validation commands in.
+    pub fn workdir(&self) -> &Path {
+        &self.workdir
+    }
+
+    /// Where `path` (relative to the starting directory) lives in the copy.
+    /// Paths outside the starting directory have no place in it.
+    pub fn path_for(&self, path: &Path) -> Result<PathBuf, Box<dyn Error>> {
+        let relative = match env::current_dir().ok().and_then(|cwd| path.strip_prefix(cwd).ok().map(Path::to_path_buf)) {
+            Some(relative) => relative,
+            None => path.to_path_buf(),
+        };
+        // Joining an absolute path would give the live file back, and `..` would leave the copy
+        if relative.components().any(|c| !matches!(c, Component::Normal(_) | Component::CurDir)) {
+            return Err(format!(
+                "{} is outside the directory copied for --isolate; run the tool from a directory containing it",
+                path.display()
+            )
+            .into());
+        }
+        Ok(self.workdir.join(relative))
+    }
+}
+
+impl Drop for Scratch {
+    fn drop(&mut self) {
+        if let Some(repo_root) = &self.repo_root {
+            let _ = ProcessCommand::new("git")
+                .arg("-C")
+                .arg(repo_root)
+                .args(["worktree", "remove", "--force"])
+                .arg(&self.root)
+                .output();
+        }
+        let _ = fs::remove_dir_all(&self.root);
+    }
+}
+
+/// Whether `path` is one of the tool's own files: its state directory, a
+/// scratch copy or another temporary file.
+pub fn is_own_file(path: &Path) -> bool {
+    if path.components().any(|c| c.as_os_str() == journal::STATE_DIR) {
+        return true;
+    }
+    let (Ok(absolute), Ok(temp)) = (path.canonicalize(), env::temp_dir().canonicalize()) else {
+        return false;
+    };
+    absolute
+        .strip_prefix(temp)
+        .ok()
+        .and_then(|relative| relative.components().next())
+        .is_some_and(|first| first.as_os_str().to_string_lossy().starts_with(TEMP_PREFIX))
+}
+
+fn create_worktree(root: PathBuf) -> Result<Scratch, Box<dyn Error>> {
+    let repo_root = PathBuf::from(git(Path::new("."), &["rev-parse", "--show-toplevel"])?.trim());
+    let cwd = env::current_dir()?;
+    let prefix = cwd.strip_prefix(&repo_root).unwrap_or(Path::new("")).to_path_buf();
+
+    git(&repo_root, &["worktree", "add", "--detach", "--quiet", &root.to_string_lossy(), "HEAD"])?;
+    let scratch = Scratch {
+        workdir: root.join(&prefix),
+        root,
+        repo_root: Some(repo_root.clone()),
+    };
+
+    // Carry over uncommitted and untracked files so the copy matches the live tree
+    let changed = git(&repo_root, &["diff", "--name-only", "-z", "HEAD"])?;
+    let untracked = git(&repo_root, &["ls-files", "--others", "--exclude-standard", "-z"])?;
+    for relative in changed.split('\0').chain(untracked.split('\0')).filter(|name| !name.is_empty()) {
+        let source = repo_root.join(relative);
+        let target = scratch.root.join(relative);
+        if source.is_file() {
+            if let Some(parent) = target.parent() {
+                fs::create_dir_all(parent)?;
+            }
+            fs::copy(&source, &target)?;
+        } else if target.exists() {
+            fs::remove_file(&target)?;
+        }
+    }
+
+    Ok(scratch)
+}
+
+fn git(dir: &Path, args: &[&str]) -> Result<String, Box<dyn Error>> {
+    let output = ProcessCommand::new("git").arg("-C").arg(dir).args(args).output()?;
+    if !output.status.success() {
+        return Err(format!("git {} failed: {}", args.join(" "), String::from_utf8_lossy(&output.stderr).trim()).into());
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+// Copies `source` into `target`, skipping `.git` and the tool's own files, which the copy does not need
+fn copy_tree(source: &Path, target: &Path) -> io::Result<()> {
+    fs::create_dir_all(target)?;
+    for entry in fs::read_dir(source)? {
+        let entry = entry?;
+        if entry.file_name() == ".git" || entry.file_name() == journal::STATE_DIR {
+            continue;
+        }
+        let file_type = entry.file_type()?;
+        let destination = target.join(entry.file_name());
+        if file_type.is_dir() {
+            copy_tree(&entry.path(), &destination)?;
+        } else if file_type.is_symlink() {
+            std::os::unix::fs::symlink(fs::read_link(entry.path())?, &destination)?;
+        } else {
+            fs::copy(entry.path(), &destination)?;
+        }
+    }
+    Ok(())
+}